@@ -0,0 +1,253 @@
+use crate::binning::BinningMode;
+use crate::bulb_calibration::BulbCalibration;
+use crate::camera::{files_per_capture, CurrentExposure, State, StopExposure};
+use crate::capture::{bulb_capture, download_capture};
+use crate::device::MyCameraDevice;
+use crate::error::convert_err;
+use crate::export::SaveFormat;
+use crate::parse_image::ImgWithMetadata;
+use crate::shutter_speed::ExposureMechanism;
+use ascom_alpaca::api::CameraState;
+use ascom_alpaca::{ASCOMError, ASCOMErrorCode, ASCOMResult};
+use atomic::{Atomic, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, watch};
+
+/// Names of the Alpaca actions handled by [`MyCameraDevice::run_action`].
+pub(crate) const SUPPORTED_ACTIONS: [&str; 5] = [
+    "ReadNoise",
+    "CalibrateBulb",
+    "Preview",
+    "TargetName",
+    "SaveImage",
+];
+
+impl MyCameraDevice {
+    /// Handle a driver-specific action; names are case-insensitive.
+    pub async fn run_action(&self, action: &str, parameters: &str) -> ASCOMResult<String> {
+        match action.to_ascii_lowercase().as_str() {
+            // ASCOM doesn't have a standard property for read noise, but it's useful for SNR calculations.
+            "readnoise" => {
+                let camera = self.camera().await?;
+                let iso = camera.iso.choice();
+                let read_noise = camera.sensor.at_iso(&iso).read_noise.ok_or_else(|| {
+                    ASCOMError::new(
+                        ASCOMErrorCode::VALUE_NOT_SET,
+                        format_args!("Read noise at ISO {iso} is not known"),
+                    )
+                })?;
+                // Noise of the bin x bin pixels adds up in quadrature to `read_noise * bin`,
+                // which averaging then divides by bin^2.
+                let bin = f64::from(camera.bin());
+                Ok(match camera.binning_mode {
+                    BinningMode::Sum => read_noise * bin,
+                    BinningMode::Average => read_noise / bin,
+                }
+                .to_string())
+            }
+            // Base64-encoded JPEG of the last exposure, for a quick look without processing RAW data.
+            "preview" => {
+                let camera = self.camera().await?;
+                let preview = camera.last_preview.lock();
+                let preview = preview.as_ref().ok_or_else(|| {
+                    ASCOMError::new(
+                        ASCOMErrorCode::VALUE_NOT_SET,
+                        "No JPEG preview available; take an exposure in a JPEG or RAW+JPEG image format",
+                    )
+                })?;
+                Ok(base64_encode(preview))
+            }
+            // Sets the target name used in archived filenames; an empty parameter clears it.
+            "targetname" => {
+                let camera = self.camera().await?;
+                let target = parameters.trim();
+                *camera.target.lock() = (!target.is_empty()).then(|| target.to_owned());
+                Ok(target.to_owned())
+            }
+            // Saves the last image in the configured directory and returns its path.
+            // Optional parameter is the format ("fits" or "xisf"), defaulting to the configured one.
+            "saveimage" => {
+                let camera = self.camera().await?;
+                let save_archive = camera.save_archive.as_ref().ok_or_else(|| {
+                    ASCOMError::invalid_operation("Image directory is not configured")
+                })?;
+                let format = match parameters.trim() {
+                    "" => camera.save_format,
+                    format => SaveFormat::parse(format).ok_or_else(|| {
+                        ASCOMError::invalid_value("Image format must be \"fits\" or \"xisf\"")
+                    })?,
+                };
+                let state = camera.state().await;
+                let State::AfterExposure(Ok(exposure)) = &*state else {
+                    return Err(ASCOMError::invalid_operation("No image available"));
+                };
+                let path = save_archive
+                    .save(
+                        &exposure.metadata.frame,
+                        format.file_name(),
+                        &format.encode(
+                            &exposure.image,
+                            &exposure.metadata,
+                            camera.xisf_compression,
+                        ),
+                    )
+                    .map_err(convert_err)?;
+                Ok(path.display().to_string())
+            }
+            // Optional parameter is the number of calibration shots.
+            "calibratebulb" => {
+                let shots = match parameters.trim() {
+                    "" => 5,
+                    shots => shots
+                        .parse()
+                        .ok()
+                        .filter(|shots| (2..=20).contains(shots))
+                        .ok_or_else(|| {
+                            ASCOMError::invalid_value("Number of shots must be between 2 and 20")
+                        })?,
+                };
+                let calibration = self.calibrate_bulb(shots).await?;
+                Ok(format!(
+                    "offset={:.3} resolution={:.3}",
+                    calibration.offset, calibration.resolution
+                ))
+            }
+            _ => Err(ASCOMError::ACTION_NOT_IMPLEMENTED),
+        }
+    }
+
+    /// Measure bulb latency by comparing hold times with exposure times recorded by the camera
+    /// over several `shots`, and remember it for future exposures.
+    ///
+    /// The shots are reported as a single exposure, which can be stopped or aborted to cancel.
+    async fn calibrate_bulb(&self, shots: usize) -> ASCOMResult<BulbCalibration> {
+        let unique_id = self
+            .assigned_id()
+            .ok_or(ASCOMError::NOT_CONNECTED)?
+            .to_owned();
+        let camera = self.camera().await?;
+        let state = Arc::clone(&camera.state);
+        let mut state_lock = camera.state().await;
+        if matches!(*state_lock, State::InExposure(_)) {
+            return Err(ASCOMError::invalid_operation("Camera is already exposing"));
+        }
+        let bulb_toggle = camera.bulb.clone().ok_or(ASCOMError::NOT_CONNECTED)?;
+        let backend = camera.backend()?;
+        if let Some(shutter_speeds) = &camera.shutter_speeds {
+            shutter_speeds
+                .select(&ExposureMechanism::Bulb)
+                .map_err(convert_err)?
+                .apply(&**backend)
+                .await
+                .map_err(convert_err)?;
+        }
+
+        // Spread hold times over a bit more than a second, so that they have different fractions
+        // of both whole and tenth seconds; that narrows down the offset if recorded times are rounded.
+        let holds = (0..shots)
+            .map(|shot| Duration::from_secs_f64(1. + 1.1 * shot as f64 / shots as f64))
+            .collect::<Vec<_>>();
+
+        let expected_files = files_per_capture(&camera.image_format.choice());
+        let keep_on_card = camera.keep_on_card;
+        let camera = Arc::clone(backend);
+        let device = self.clone();
+        let (stop_tx, mut stop_rx) = oneshot::channel::<StopExposure>();
+        let (done_tx, done_rx) = watch::channel(false);
+        let exposing_state = Arc::new(Atomic::new(CameraState::Waiting));
+
+        *state_lock = State::InExposure(CurrentExposure {
+            rough_start: Instant::now(),
+            state: Arc::clone(&exposing_state),
+            stop_tx: Some(stop_tx),
+            done_rx,
+            expected_duration: holds.iter().sum(),
+        });
+        drop(state_lock);
+
+        let task = tokio::task::spawn(async move {
+            let result = async {
+                let mut samples = Vec::with_capacity(shots);
+                for hold in holds {
+                    // Either way of stopping cancels the whole run.
+                    if stop_rx.try_recv().is_ok() {
+                        return Err(ASCOMError::invalid_operation(
+                            "Bulb calibration was aborted",
+                        ));
+                    }
+                    let stop = async {
+                        match (&mut stop_rx).await {
+                            Ok(_) => false,
+                            Err(_) => std::future::pending().await,
+                        }
+                    };
+                    let (files, _) = bulb_capture(
+                        &*camera,
+                        bulb_toggle.clone(),
+                        hold,
+                        expected_files,
+                        stop,
+                        &exposing_state,
+                    )
+                    .await?;
+                    exposing_state.store(CameraState::Download, Ordering::Relaxed);
+                    let (data, _) = download_capture(&*camera, files, keep_on_card, None)
+                        .await
+                        .map_err(convert_err)?;
+                    let exposure_time = ImgWithMetadata::from_data(data)
+                        .map_err(convert_err)?
+                        .exposure_time
+                        .ok_or_else(|| {
+                            ASCOMError::unspecified("Camera doesn't record exposure time in images")
+                        })?;
+                    tracing::debug!(?hold, exposure_time, "Bulb calibration shot");
+                    samples.push((hold.as_secs_f64(), exposure_time));
+                }
+                BulbCalibration::from_samples(&samples).map_err(convert_err)
+            }
+            .await;
+
+            let connection_lost =
+                matches!(&result, Err(err) if err.code == ASCOMErrorCode::NOT_CONNECTED);
+
+            // Calibration shots aren't meant to be downloaded as images.
+            *state.lock().await = State::Idle;
+
+            let _ = done_tx.send(true);
+
+            if connection_lost {
+                // Let go of the camera first, so that it can be opened again.
+                drop((camera, bulb_toggle));
+                device.connection_lost().await;
+            }
+
+            result
+        });
+
+        let calibration = task.await.map_err(ASCOMError::unspecified)??;
+        tracing::info!(?calibration, "Bulb calibration complete");
+        self.bulb_calibrations
+            .set(&unique_id, calibration)
+            .map_err(convert_err)?;
+        Ok(calibration)
+    }
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0_u32, |bits, (i, &byte)| {
+            bits | u32::from(byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            encoded.push(match i <= chunk.len() {
+                true => ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize].into(),
+                false => '=',
+            });
+        }
+    }
+    encoded
+}
@@ -0,0 +1,228 @@
+use crate::config::CameraConfig;
+use crate::error::DriverError;
+use eyre::{Context, ContextCompat};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+const DEFAULT_TEMPLATE: &str = "{date}/{target}_{frame}_{exposure}s_ISO{iso}_{time}";
+
+const PLACEHOLDERS: [&str; 7] = [
+    "date", "time", "target", "frame", "exposure", "iso", "filename",
+];
+
+/// Details of an exposure used in archived filenames.
+#[derive(Debug)]
+pub(crate) struct FrameInfo {
+    pub start: SystemTime,
+    pub exposure: f64,
+    pub iso: String,
+    pub light: bool,
+    /// Whether this is a dark frame with zero exposure requested.
+    pub bias: bool,
+    pub target: Option<String>,
+}
+
+impl FrameInfo {
+    /// Type of the frame in the usual `IMAGETYP` FITS keyword values.
+    pub fn image_type(&self) -> &'static str {
+        match (self.light, self.bias) {
+            (true, _) => "Light Frame",
+            (false, true) => "Bias Frame",
+            (false, false) => "Dark Frame",
+        }
+    }
+}
+
+/// Local directory where original camera files are saved.
+#[derive(Debug)]
+pub(crate) struct Archive {
+    pub dir: PathBuf,
+    /// Path relative to `dir` with `{placeholder}`s; the original extension is appended.
+    pub template: String,
+    /// Space to leave free on the disk, in bytes.
+    pub min_free_space: u64,
+}
+
+/// Substitute `{name}` placeholders in `template` with values returned by `value`.
+fn render(template: &str, mut value: impl FnMut(&str) -> Option<String>) -> eyre::Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let len = rest[start..]
+            .find('}')
+            .context("Unclosed placeholder in archive template")?;
+        let name = &rest[start + 1..start + len];
+        rendered.push_str(
+            &value(name)
+                .with_context(|| format!("Unknown placeholder {{{name}}} in archive template"))?,
+        );
+        rest = &rest[start + len + 1..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+pub(crate) fn validate_template(template: &str) -> eyre::Result<()> {
+    render(template, |name| {
+        PLACEHOLDERS.contains(&name).then(String::new)
+    })?;
+    eyre::ensure!(
+        Path::new(template)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_))),
+        "Archive template must be a relative path within the archive directory"
+    );
+    Ok(())
+}
+
+/// Make a value from a client or the camera safe to use as a path component.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' | '+' => c,
+            _ => '_',
+        })
+        .collect::<String>()
+        .trim_start_matches('.')
+        .to_owned()
+}
+
+/// Seconds with up to millisecond precision and without trailing zeros, e.g. "0.5" or "300".
+fn format_exposure(exposure: f64) -> String {
+    let formatted = format!("{exposure:.3}");
+    formatted
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_owned()
+}
+
+/// Space available to unprivileged users on the disk containing `dir`, in bytes.
+#[cfg(unix)]
+fn available_space(dir: &Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid C string and `stat` is valid for writes.
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: `statvfs` succeeded, so it has filled in `stat`.
+    let stat = unsafe { stat.assume_init() };
+    // Field types differ between platforms.
+    #[allow(clippy::useless_conversion)]
+    Ok(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+}
+
+#[cfg(not(unix))]
+fn available_space(_dir: &Path) -> std::io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+impl Archive {
+    pub fn new(dir: &Path, config: &CameraConfig) -> Self {
+        Self {
+            dir: dir.to_owned(),
+            template: config
+                .archive_template
+                .clone()
+                .unwrap_or_else(|| DEFAULT_TEMPLATE.to_owned()),
+            min_free_space: config.archive_min_free_space.unwrap_or(1024) * 1_000_000,
+        }
+    }
+
+    fn path_for(&self, frame: &FrameInfo, original_name: &str) -> eyre::Result<PathBuf> {
+        let original = Path::new(original_name);
+        let start = time::OffsetDateTime::from(frame.start);
+        let relative = render(&self.template, |name| {
+            Some(match name {
+                "date" => format!(
+                    "{:04}-{:02}-{:02}",
+                    start.year(),
+                    u8::from(start.month()),
+                    start.day()
+                ),
+                "time" => format!(
+                    "{:02}-{:02}-{:02}",
+                    start.hour(),
+                    start.minute(),
+                    start.second()
+                ),
+                "target" => sanitize(frame.target.as_deref().unwrap_or("untitled")),
+                "frame" => match frame.light {
+                    true => "light",
+                    false => "dark",
+                }
+                .to_owned(),
+                "exposure" => format_exposure(frame.exposure),
+                "iso" => sanitize(&frame.iso),
+                "filename" => sanitize(&original.file_stem()?.to_string_lossy()),
+                _ => return None,
+            })
+        })?;
+        let mut path = self.dir.join(relative);
+        if let Some(extension) = original.extension() {
+            path.as_mut_os_string().push(".");
+            path.as_mut_os_string().push(extension);
+        }
+        Ok(path)
+    }
+
+    /// Save original file `data` under a name derived from the template, returning the path.
+    pub fn save(
+        &self,
+        frame: &FrameInfo,
+        original_name: &str,
+        data: &[u8],
+    ) -> eyre::Result<PathBuf> {
+        let path = self.path_for(frame, original_name)?;
+        let parent = path.parent().unwrap_or(&self.dir);
+        std::fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("Couldn't create directory {}", parent.display()))?;
+
+        let available = available_space(parent)
+            .wrap_err_with(|| format!("Couldn't check free space in {}", parent.display()))?;
+        if available.saturating_sub(data.len() as u64) < self.min_free_space {
+            return Err(
+                eyre::Report::new(DriverError::StorageFull).wrap_err(format!(
+                    "Not enough free space in {} ({} MB available)",
+                    parent.display(),
+                    available / 1_000_000
+                )),
+            );
+        }
+
+        // Don't overwrite frames with the same name, e.g. from the same second.
+        let mut unique_path = path.clone();
+        let mut n = 0;
+        loop {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&unique_path)
+            {
+                Ok(mut file) => {
+                    std::io::Write::write_all(&mut file, data)
+                        .wrap_err_with(|| format!("Couldn't write {}", unique_path.display()))?;
+                    return Ok(unique_path);
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                    n += 1;
+                    let mut name = path.file_stem().unwrap_or_default().to_owned();
+                    name.push(format!("_{n}"));
+                    if let Some(extension) = path.extension() {
+                        name.push(".");
+                        name.push(extension);
+                    }
+                    unique_path.set_file_name(name);
+                }
+                Err(err) => {
+                    return Err(err)
+                        .wrap_err_with(|| format!("Couldn't create {}", unique_path.display()))
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use crate::camera::{is_raw_format, CurrentExposure, State, Temperature};
+use crate::device::MyCameraDevice;
+use crate::{binning, bulb_calibration};
+use ascom_alpaca::api::{Camera, CameraState, ImageArray, SensorType};
+use ascom_alpaca::{ASCOMError, ASCOMErrorCode, ASCOMResult};
+use async_trait::async_trait;
+use atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime};
+
+#[allow(unused_variables)]
+#[async_trait]
+impl Camera for MyCameraDevice {
+    async fn bayer_offset_x(&self) -> ASCOMResult<i32> {
+        Ok(self.camera().await?.bayer_offset()?.0 as _)
+    }
+
+    async fn bayer_offset_y(&self) -> ASCOMResult<i32> {
+        Ok(self.camera().await?.bayer_offset()?.1 as _)
+    }
+
+    // Binning is symmetric, so setting either axis changes both.
+
+    async fn bin_x(&self) -> ASCOMResult<i32> {
+        Ok(self.camera().await?.bin() as _)
+    }
+
+    async fn set_bin_x(&self, bin_x: i32) -> ASCOMResult {
+        self.camera().await?.set_bin(bin_x)
+    }
+
+    async fn bin_y(&self) -> ASCOMResult<i32> {
+        Ok(self.camera().await?.bin() as _)
+    }
+
+    async fn set_bin_y(&self, bin_y: i32) -> ASCOMResult {
+        self.camera().await?.set_bin(bin_y)
+    }
+
+    async fn camera_state(&self) -> ASCOMResult<CameraState> {
+        let camera = self.camera().await?;
+        if camera.link_lost.load(Ordering::Relaxed) {
+            return Ok(CameraState::Error);
+        }
+        Ok(match &*camera.state().await {
+            State::Idle => CameraState::Idle,
+            State::InExposure(exposure) => exposure.state.load(Ordering::Relaxed),
+            State::AfterExposure(result) => match result {
+                Ok(_) => CameraState::Idle,
+                Err(_) => CameraState::Error,
+            },
+        })
+    }
+
+    async fn camera_xsize(&self) -> ASCOMResult<i32> {
+        Ok(self.camera().await?.dimensions()?.width as _)
+    }
+
+    async fn camera_ysize(&self) -> ASCOMResult<i32> {
+        Ok(self.camera().await?.dimensions()?.height as _)
+    }
+
+    async fn can_abort_exposure(&self) -> ASCOMResult<bool> {
+        Ok(true)
+    }
+
+    async fn can_stop_exposure(&self) -> ASCOMResult<bool> {
+        Ok(true)
+    }
+
+    async fn ccd_temperature(&self) -> ASCOMResult<f64> {
+        let camera = self.camera().await?;
+
+        // Reading config in the middle of an exposure might fail with "camera busy" or even
+        // interfere with it, so rely on the last frame's maker notes instead.
+        if let Some(backend) = camera
+            .backend
+            .as_ref()
+            .filter(|backend| backend.has_temperature())
+        {
+            if !matches!(*camera.state().await, State::InExposure(_)) {
+                match backend.temperature().await {
+                    Ok(celsius) => {
+                        *camera.last_temperature.lock() = Some(Temperature {
+                            celsius,
+                            measured_at: Instant::now(),
+                        });
+                    }
+                    Err(err) => tracing::debug!("Couldn't read temperature: {err:#}"),
+                }
+            }
+        }
+
+        let last_temperature = *camera.last_temperature.lock();
+        match last_temperature {
+            Some(temperature)
+                if temperature.measured_at.elapsed() <= camera.temperature_max_age =>
+            {
+                Ok(temperature.celsius)
+            }
+            Some(temperature) => Err(ASCOMError::new(
+                ASCOMErrorCode::VALUE_NOT_SET,
+                format_args!(
+                    "Last known temperature is stale ({:.0}s old), take an exposure to update it",
+                    temperature.measured_at.elapsed().as_secs_f64()
+                ),
+            )),
+            None => Err(ASCOMError::new(
+                ASCOMErrorCode::VALUE_NOT_SET,
+                "Camera temperature is not known yet, take an exposure first",
+            )),
+        }
+    }
+
+    async fn electrons_per_adu(&self) -> ASCOMResult<f64> {
+        // This property is mandatory, so fall back to 1 e-/ADU for sensors missing from the database.
+        Ok(self.camera().await?.electrons_per_adu().unwrap_or(1.))
+    }
+
+    async fn exposure_max(&self) -> ASCOMResult<f64> {
+        Ok(100. * 60. * 60.)
+    }
+
+    async fn exposure_min(&self) -> ASCOMResult<f64> {
+        Ok(self.camera().await?.shortest_exposure())
+    }
+
+    async fn exposure_resolution(&self) -> ASCOMResult<f64> {
+        let camera = self.camera().await?;
+        // Arbitrary durations are down to bulb timing, which is only known once measured.
+        let bulb = self
+            .bulb_calibration()
+            .map_or(bulb_calibration::UNCALIBRATED_RESOLUTION, |calibration| {
+                calibration.resolution
+            });
+        // Shorter exposures snap to the closest discrete shutter speed instead.
+        let timed = camera
+            .shutter_speeds
+            .as_ref()
+            .map_or(Duration::ZERO, |shutter_speeds| {
+                shutter_speeds.largest_step(camera.min_bulb_duration)
+            });
+        // Clients can only ask for a single resolution across the whole range.
+        Ok(bulb.max(timed.as_secs_f64()))
+    }
+
+    async fn max_adu(&self) -> ASCOMResult<i32> {
+        Ok(self.camera().await?.max_adu.load(Ordering::Relaxed) as _)
+    }
+
+    async fn full_well_capacity(&self) -> ASCOMResult<f64> {
+        let camera = self.camera().await?;
+        let max_signal = f64::from(camera.max_signal.load(Ordering::Relaxed));
+        // Binned pixels collect light from several sensor pixels.
+        let sensor_full_well = camera
+            .sensor
+            .full_well
+            .map(|full_well| full_well * f64::from(camera.bin() * camera.bin()));
+        Ok(match (camera.electrons_per_adu(), sensor_full_well) {
+            // At higher ISOs the ADC saturates before the pixel wells do.
+            (Some(gain), Some(full_well)) => (max_signal * gain).min(full_well),
+            (Some(gain), None) => max_signal * gain,
+            (None, Some(full_well)) => full_well,
+            // Assume 1 e-/ADU.
+            (None, None) => max_signal,
+        })
+    }
+
+    async fn gain(&self) -> ASCOMResult<i32> {
+        self.camera().await?.iso.choice_idx()
+    }
+
+    async fn set_gain(&self, gain: i32) -> ASCOMResult {
+        self.camera().await?.iso.set_choice_idx(gain)
+    }
+
+    async fn gains(&self) -> ASCOMResult<Vec<String>> {
+        Ok(self.camera().await?.iso.choices().to_vec())
+    }
+
+    async fn has_shutter(&self) -> ASCOMResult<bool> {
+        Ok(true)
+    }
+
+    async fn image_array(&self) -> ASCOMResult<ImageArray> {
+        match &*self.camera().await?.state().await {
+            // The array is reference-counted, so all clients share the same pixels.
+            State::AfterExposure(Ok(exposure)) => Ok(exposure.image.clone()),
+            _ => Err(ASCOMError::INVALID_OPERATION),
+        }
+    }
+
+    async fn image_ready(&self) -> ASCOMResult<bool> {
+        Ok(matches!(
+            *self.camera().await?.state().await,
+            State::AfterExposure(Ok(_))
+        ))
+    }
+
+    async fn last_exposure_duration(&self) -> ASCOMResult<f64> {
+        self.camera()
+            .await?
+            .last_exposure_duration
+            .load(Ordering::Relaxed)
+            .ok_or(ASCOMError::INVALID_OPERATION)
+    }
+
+    async fn last_exposure_start_time(&self) -> ASCOMResult<SystemTime> {
+        self.camera()
+            .await?
+            .last_exposure_start_time
+            .load(Ordering::Relaxed)
+            .ok_or(ASCOMError::INVALID_OPERATION)
+    }
+
+    async fn max_bin_x(&self) -> ASCOMResult<i32> {
+        Ok(binning::MAX_BIN.into())
+    }
+
+    async fn max_bin_y(&self) -> ASCOMResult<i32> {
+        Ok(binning::MAX_BIN.into())
+    }
+
+    async fn start_x(&self) -> ASCOMResult<i32> {
+        Ok(self.camera().await?.subframe()?.x as _)
+    }
+
+    async fn set_start_x(&self, start_x: i32) -> ASCOMResult {
+        self.camera()
+            .await?
+            .update_subframe(start_x, |subframe| &mut subframe.x)
+    }
+
+    async fn start_y(&self) -> ASCOMResult<i32> {
+        Ok(self.camera().await?.subframe()?.y as _)
+    }
+
+    async fn set_start_y(&self, start_y: i32) -> ASCOMResult {
+        self.camera()
+            .await?
+            .update_subframe(start_y, |subframe| &mut subframe.y)
+    }
+
+    async fn num_x(&self) -> ASCOMResult<i32> {
+        Ok(self.camera().await?.subframe()?.width as _)
+    }
+
+    async fn set_num_x(&self, num_x: i32) -> ASCOMResult {
+        self.camera()
+            .await?
+            .update_subframe(num_x, |subframe| &mut subframe.width)
+    }
+
+    async fn num_y(&self) -> ASCOMResult<i32> {
+        Ok(self.camera().await?.subframe()?.height as _)
+    }
+
+    async fn set_num_y(&self, num_y: i32) -> ASCOMResult {
+        self.camera()
+            .await?
+            .update_subframe(num_y, |subframe| &mut subframe.height)
+    }
+
+    async fn percent_completed(&self) -> ASCOMResult<i32> {
+        Ok(match &*self.camera().await?.state().await {
+            State::Idle => 0,
+            State::InExposure(CurrentExposure {
+                rough_start: start,
+                expected_duration,
+                ..
+            }) => {
+                let elapsed = start.elapsed().as_secs_f64();
+                let max = expected_duration.as_secs_f64();
+                (100.0 * (elapsed / max).min(1.0)).round() as i32
+            }
+            State::AfterExposure(_) => 100,
+        })
+    }
+
+    async fn readout_mode(&self) -> ASCOMResult<i32> {
+        let camera = self.camera().await?;
+        let current = (
+            camera.image_format.choice_idx()? as usize,
+            camera.debayer.load(Ordering::Relaxed) && is_raw_format(&camera.image_format.choice()),
+        );
+        camera
+            .readout_modes()
+            .iter()
+            .position(|&mode| mode == current)
+            .map(|index| index as _)
+            .ok_or_else(|| ASCOMError::unspecified("current readout mode not found"))
+    }
+
+    async fn set_readout_mode(&self, readout_mode: i32) -> ASCOMResult {
+        let camera = self.camera().await?;
+        let (image_format, debayer) = usize::try_from(readout_mode)
+            .ok()
+            .and_then(|index| camera.readout_modes().get(index).copied())
+            .ok_or_else(|| ASCOMError::invalid_value("readout mode index out of range"))?;
+        camera.image_format.set_choice_idx(image_format as _)?;
+        camera.debayer.store(debayer, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn readout_modes(&self) -> ASCOMResult<Vec<String>> {
+        let camera = self.camera().await?;
+        let choices = camera.image_format.choices();
+        Ok(camera
+            .readout_modes()
+            .into_iter()
+            .map(|(index, debayer)| match debayer {
+                true => format!("{} (debayered)", choices[index]),
+                false => choices[index].clone(),
+            })
+            .collect())
+    }
+
+    async fn sensor_name(&self) -> ASCOMResult<String> {
+        Ok(self.camera().await?.sensor.name.clone().unwrap_or_default())
+    }
+
+    async fn pixel_size_x(&self) -> ASCOMResult<f64> {
+        self.camera().await?.pixel_size()
+    }
+
+    async fn pixel_size_y(&self) -> ASCOMResult<f64> {
+        self.camera().await?.pixel_size()
+    }
+
+    async fn sensor_type(&self) -> ASCOMResult<SensorType> {
+        Ok(self.camera().await?.sensor_type())
+    }
+
+    async fn start_exposure(&self, duration: f64, light: bool) -> ASCOMResult {
+        self.expose(duration, light).await
+    }
+
+    async fn stop_exposure(&self) -> ASCOMResult {
+        self.stop(true).await
+    }
+
+    async fn abort_exposure(&self) -> ASCOMResult {
+        self.stop(false).await
+    }
+}
@@ -0,0 +1,123 @@
+use crate::config::SimulatorConfig;
+use crate::gphoto2_backend::Gphoto2Camera;
+use crate::simulator::SimulatedCamera;
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// File on the camera's card.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CameraFile {
+    pub folder: String,
+    pub name: String,
+}
+
+/// Event reported by the camera, e.g. while it finishes a capture.
+#[derive(Debug)]
+pub(crate) enum CameraEvent {
+    NewFile(CameraFile),
+    /// Nothing happened within the given timeout.
+    Timeout,
+    /// Anything we don't act upon.
+    Other(String),
+}
+
+/// Current value and available choices of a radio config widget.
+#[derive(Debug)]
+pub(crate) struct RadioConfig {
+    pub choices: Vec<String>,
+    pub current: String,
+}
+
+/// Camera hardware as seen by the driver: capture, config widgets, events and file download.
+#[async_trait]
+pub(crate) trait CameraBackend: std::fmt::Debug + Send + Sync {
+    /// Read the radio config widget `name`, e.g. "iso" or "shutterspeed".
+    async fn radio_config(&self, name: &str) -> eyre::Result<RadioConfig>;
+
+    async fn set_radio_config(&self, name: &str, value: &str) -> eyre::Result<()>;
+
+    /// Serial number, if the camera reports a meaningful one.
+    async fn serial_number(&self) -> Option<String>;
+
+    /// Whether the shutter can be held open for bulb exposures.
+    fn supports_bulb(&self) -> bool;
+
+    /// Open or close the shutter in bulb mode.
+    async fn set_bulb(&self, on: bool) -> eyre::Result<()>;
+
+    /// Like [`CameraBackend::set_bulb`], but blocking the current thread until the camera responds.
+    ///
+    /// For places where we can't await, like dropping an exposure on panic or shutdown.
+    fn set_bulb_blocking(&self, on: bool) -> eyre::Result<()>;
+
+    /// Whether the camera reports its temperature via config.
+    fn has_temperature(&self) -> bool;
+
+    /// Camera temperature in °C.
+    async fn temperature(&self) -> eyre::Result<f64>;
+
+    /// Take a picture at the current shutter speed and return the first file it produced.
+    ///
+    /// Other files of the same capture (e.g. the JPEG of RAW+JPEG) are reported as events.
+    async fn capture(&self) -> eyre::Result<CameraFile>;
+
+    async fn wait_event(&self, timeout: Duration) -> eyre::Result<CameraEvent>;
+
+    async fn download(&self, file: &CameraFile) -> eyre::Result<Bytes>;
+
+    async fn delete(&self, file: &CameraFile) -> eyre::Result<()>;
+}
+
+/// Where to find an attached camera.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum CameraDescriptor {
+    Gphoto2(gphoto2::list::CameraDescriptor),
+    Simulated {
+        index: usize,
+        config: Arc<SimulatorConfig>,
+    },
+}
+
+impl CameraDescriptor {
+    pub fn model(&self) -> &str {
+        match self {
+            Self::Gphoto2(descriptor) => &descriptor.model,
+            Self::Simulated { config, .. } => &config.model,
+        }
+    }
+
+    pub fn port(&self) -> String {
+        match self {
+            Self::Gphoto2(descriptor) => descriptor.port.clone(),
+            Self::Simulated { index, .. } => format!("simulator:{index}"),
+        }
+    }
+
+    pub async fn open(&self) -> eyre::Result<Arc<dyn CameraBackend>> {
+        Ok(match self {
+            Self::Gphoto2(descriptor) => Arc::new(Gphoto2Camera::open(descriptor).await?),
+            Self::Simulated { index, config } => {
+                Arc::new(SimulatedCamera::new(*index, Arc::clone(config)))
+            }
+        })
+    }
+}
+
+/// List attached cameras: those detected by gphoto2 followed by `simulator.cameras` simulated ones.
+pub(crate) async fn list_cameras(
+    simulator: &Arc<SimulatorConfig>,
+) -> eyre::Result<Vec<CameraDescriptor>> {
+    let mut cameras = crate::gphoto2_backend::list_cameras()
+        .await?
+        .map(CameraDescriptor::Gphoto2)
+        .collect::<Vec<_>>();
+    cameras.extend(
+        (0..simulator.cameras).map(|index| CameraDescriptor::Simulated {
+            index,
+            config: Arc::clone(simulator),
+        }),
+    );
+    Ok(cameras)
+}
@@ -0,0 +1,169 @@
+use image::{DynamicImage, ImageBuffer, Luma, Pixel, Rgb};
+use rawler::CFA;
+use serde::Deserialize;
+
+pub(crate) const MAX_BIN: u8 = 4;
+
+/// How pixel values within a bin are combined.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BinningMode {
+    /// Sum of the pixels, saturating at the 16-bit maximum.
+    Sum,
+    /// Mean of the pixels, preserving the original value range.
+    #[default]
+    Average,
+}
+
+/// What binning of Bayer RAW frames produces.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BayerBinning {
+    /// Combine each colour separately into an RGB superpixel.
+    Color,
+    /// Combine all colours into a single monochrome value.
+    #[default]
+    Mono,
+}
+
+fn combine(sum: u64, count: u64, bin_area: u64, mode: BinningMode) -> u16 {
+    if count == 0 {
+        return 0;
+    }
+    let value = match mode {
+        BinningMode::Sum => sum * bin_area / count,
+        BinningMode::Average => sum / count,
+    };
+    value.min(u16::MAX.into()) as u16
+}
+
+fn bin_buffer<P: Pixel>(
+    img: &ImageBuffer<P, Vec<P::Subpixel>>,
+    factor: u32,
+    mode: BinningMode,
+    cfa: Option<(&CFA, BayerBinning)>,
+) -> DynamicImage
+where
+    P::Subpixel: Into<u64>,
+{
+    let width = img.width() / factor;
+    let height = img.height() / factor;
+    let bin_area = u64::from(factor * factor);
+
+    let channels = match cfa {
+        Some((_, BayerBinning::Color)) => 3,
+        Some((_, BayerBinning::Mono)) => 1,
+        None => usize::from(P::CHANNEL_COUNT),
+    };
+
+    let mut samples = Vec::with_capacity(width as usize * height as usize * channels);
+
+    for bin_y in 0..height {
+        for bin_x in 0..width {
+            // Indexed by the image channel, or by the CFA colour for RAW frames.
+            let mut sums = [0_u64; 4];
+            let mut counts = [0_u64; 4];
+
+            for y in bin_y * factor..(bin_y + 1) * factor {
+                for x in bin_x * factor..(bin_x + 1) * factor {
+                    let pixel = img.get_pixel(x, y).channels();
+                    match cfa {
+                        Some((cfa, _)) => {
+                            let color = cfa.color_at(y as usize, x as usize);
+                            sums[color] += pixel[0].into();
+                            counts[color] += 1;
+                        }
+                        None => {
+                            for (channel, &value) in pixel.iter().enumerate() {
+                                sums[channel] += value.into();
+                                counts[channel] += 1;
+                            }
+                        }
+                    }
+                }
+            }
+
+            match cfa {
+                Some((_, BayerBinning::Mono)) => samples.push(combine(
+                    sums.iter().sum(),
+                    counts.iter().sum(),
+                    bin_area,
+                    mode,
+                )),
+                _ => samples.extend(
+                    (0..channels)
+                        .map(|channel| combine(sums[channel], counts[channel], bin_area, mode)),
+                ),
+            }
+        }
+    }
+
+    match channels {
+        1 => ImageBuffer::<Luma<u16>, _>::from_raw(width, height, samples)
+            .expect("binned buffer size mismatch")
+            .into(),
+        _ => ImageBuffer::<Rgb<u16>, _>::from_raw(width, height, samples)
+            .expect("binned buffer size mismatch")
+            .into(),
+    }
+}
+
+/// Bin `image` by `factor` in both directions, dropping incomplete bins at the right and bottom edges.
+///
+/// For RAW frames `cfa` is the colour filter pattern at the image origin; each bin is then combined
+/// per colour into a superpixel (or into a single value for mono output) instead of mixing colours blindly.
+pub(crate) fn bin_image(
+    image: &DynamicImage,
+    factor: u32,
+    mode: BinningMode,
+    cfa: Option<(&CFA, BayerBinning)>,
+) -> eyre::Result<DynamicImage> {
+    Ok(match image {
+        DynamicImage::ImageLuma8(img) => bin_buffer(img, factor, mode, cfa),
+        DynamicImage::ImageLuma16(img) => bin_buffer(img, factor, mode, cfa),
+        DynamicImage::ImageRgb8(img) if cfa.is_none() => bin_buffer(img, factor, mode, None),
+        DynamicImage::ImageRgb16(img) if cfa.is_none() => bin_buffer(img, factor, mode, None),
+        _ => eyre::bail!("unsupported image colour format for binning"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_incomplete_bins() {
+        #[rustfmt::skip]
+        let image = ImageBuffer::<Luma<u16>, _>::from_raw(5, 3, vec![
+            1, 3, 2, 2, 9,
+            1, 3, 2, 6, 9,
+            9, 9, 9, 9, 9,
+        ]).unwrap().into();
+        let binned = bin_image(&image, 2, BinningMode::Average, None).unwrap();
+        assert_eq!(binned.as_luma16().unwrap().as_raw(), &[2, 3]);
+
+        let binned = bin_image(&image, 2, BinningMode::Sum, None).unwrap();
+        assert_eq!(binned.as_luma16().unwrap().as_raw(), &[8, 12]);
+    }
+
+    #[test]
+    fn bayer_superpixels() {
+        // R G
+        // G B
+        let image = ImageBuffer::<Luma<u16>, _>::from_raw(2, 2, vec![100, 200, 400, 50])
+            .unwrap()
+            .into();
+        let cfa = CFA::new("RGGB");
+
+        let color = Some((&cfa, BayerBinning::Color));
+        let binned = bin_image(&image, 2, BinningMode::Average, color).unwrap();
+        assert_eq!(binned.as_rgb16().unwrap().as_raw(), &[100, 300, 50]);
+        // Sums are scaled to the whole bin, as if every pixel had each colour.
+        let binned = bin_image(&image, 2, BinningMode::Sum, color).unwrap();
+        assert_eq!(binned.as_rgb16().unwrap().as_raw(), &[400, 1200, 200]);
+
+        let mono = Some((&cfa, BayerBinning::Mono));
+        let binned = bin_image(&image, 2, BinningMode::Average, mono).unwrap();
+        assert_eq!(binned.as_luma16().unwrap().as_raw(), &[187]);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::state_file;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// `ExposureResolution` to report before bulb timing is calibrated: USB round trips to open
+/// and close the shutter can easily take a few hundred milliseconds.
+pub(crate) const UNCALIBRATED_RESOLUTION: f64 = 0.5;
+
+/// Measured timing of bulb exposures on a particular camera.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct BulbCalibration {
+    /// How much longer, in seconds, actual exposures are than the time bulb is held for.
+    pub offset: f64,
+    /// Largest deviation of actual exposure times from the corrected ones, in seconds.
+    pub resolution: f64,
+}
+
+impl BulbCalibration {
+    /// Derive calibration from pairs of bulb hold time and exposure time recorded by the camera.
+    ///
+    /// Many bodies round the times they record (e.g. to tenths of a second), so each sample only
+    /// narrows the offset down to an interval around its error. With hold times spread over a
+    /// second, the intervals overlap in a much narrower range, and the offset is taken from the
+    /// middle of it. If jitter makes the intervals disjoint, the middle of the gap is used instead.
+    pub fn from_samples(samples: &[(f64, f64)]) -> eyre::Result<Self> {
+        eyre::ensure!(
+            samples.len() >= 2,
+            "Need at least two exposures to calibrate bulb timing"
+        );
+        let (mut low, mut high) = (f64::NEG_INFINITY, f64::INFINITY);
+        for &(hold, actual) in samples {
+            let error = actual - hold;
+            let rounding = rounding(actual);
+            low = low.max(error - rounding);
+            high = high.min(error + rounding);
+        }
+        Ok(Self {
+            offset: (low + high) / 2.,
+            // Exposure times are recorded with limited precision anyway.
+            resolution: ((high - low).abs() / 2.).max(0.001),
+        })
+    }
+
+    /// How long to hold bulb for to get an exposure of `duration`.
+    pub fn hold_time(&self, duration: Duration) -> Duration {
+        Duration::try_from_secs_f64(duration.as_secs_f64() - self.offset).unwrap_or_default()
+    }
+
+    /// Expected exposure time for bulb held for `hold`.
+    pub fn exposure_time(&self, hold: Duration) -> f64 {
+        (hold.as_secs_f64() + self.offset).max(0.)
+    }
+}
+
+/// How far `exposure_time` might be from the actual one, judging by the precision it's recorded with.
+fn rounding(exposure_time: f64) -> f64 {
+    [1., 0.1, 0.01]
+        .into_iter()
+        .find(|&step| {
+            let steps = exposure_time / step;
+            (steps - steps.round()).abs() < 1e-6
+        })
+        .map_or(0., |step| step / 2.)
+}
+
+/// Bulb calibrations keyed by camera unique ID, persisted in the state directory.
+#[derive(Debug)]
+pub(crate) struct BulbCalibrations {
+    path: PathBuf,
+    calibrations: parking_lot::Mutex<BTreeMap<String, BulbCalibration>>,
+}
+
+impl BulbCalibrations {
+    pub fn load(state_dir: &std::path::Path) -> eyre::Result<Self> {
+        let path = state_dir.join("bulb_calibration.toml");
+        Ok(Self {
+            calibrations: parking_lot::Mutex::new(state_file::load(&path)?),
+            path,
+        })
+    }
+
+    pub fn get(&self, unique_id: &str) -> Option<BulbCalibration> {
+        self.calibrations.lock().get(unique_id).copied()
+    }
+
+    pub fn set(&self, unique_id: &str, calibration: BulbCalibration) -> eyre::Result<()> {
+        let mut calibrations = self.calibrations.lock();
+        calibrations.insert(unique_id.to_owned(), calibration);
+        state_file::save(&self.path, &*calibrations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn precise_times() {
+        let calibration =
+            BulbCalibration::from_samples(&[(1., 1.112), (1.5, 1.633), (2., 2.131)]).unwrap();
+        // Errors range from 0.112 to 0.133.
+        assert!((calibration.offset - 0.1225).abs() < 1e-9);
+        assert!((calibration.resolution - 0.0105).abs() < 1e-9);
+    }
+
+    #[test]
+    fn times_rounded_to_tenths() {
+        // Offset of 0.123s with hold times spread as in a calibration run of 5 shots.
+        let samples = [(1., 1.1), (1.22, 1.3), (1.44, 1.6), (1.66, 1.8), (1.88, 2.)];
+        let calibration = BulbCalibration::from_samples(&samples).unwrap();
+        assert!((calibration.offset - 0.12).abs() < 1e-9);
+        assert!((calibration.resolution - 0.01).abs() < 1e-9);
+    }
+
+    #[test]
+    fn persisted_per_camera() {
+        let state_dir = std::env::temp_dir().join(format!(
+            "{}-persisted_per_camera-{}",
+            env!("CARGO_PKG_NAME"),
+            std::process::id()
+        ));
+        let calibration = BulbCalibration {
+            offset: 0.1,
+            resolution: 0.02,
+        };
+        BulbCalibrations::load(&state_dir)
+            .unwrap()
+            .set("camera", calibration)
+            .unwrap();
+
+        let reloaded = BulbCalibrations::load(&state_dir).unwrap();
+        std::fs::remove_dir_all(state_dir).unwrap();
+        assert_eq!(reloaded.get("camera").unwrap().offset, 0.1);
+        assert!(reloaded.get("other").is_none());
+    }
+}
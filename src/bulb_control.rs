@@ -1,63 +1,69 @@
-use gphoto2::widget::{RadioWidget, ToggleWidget};
-use gphoto2::Camera;
+use crate::backend::CameraBackend;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
-enum BulbControlKind {
-    Standard(ToggleWidget),
-    EosRemoteRelease(RadioWidget),
-}
-
-#[derive(custom_debug::Debug, Clone)]
 pub(crate) struct BulbControl {
-    kind: BulbControlKind,
-    #[debug(skip)]
-    camera: Camera,
+    camera: Arc<dyn CameraBackend>,
 }
 
 impl BulbControl {
-    pub async fn new(camera: &Camera) -> eyre::Result<Self> {
+    pub fn new(camera: &Arc<dyn CameraBackend>) -> eyre::Result<Self> {
+        eyre::ensure!(
+            camera.supports_bulb(),
+            "Camera does not support bulb exposures"
+        );
         Ok(Self {
-            kind: if let Ok(toggle) = camera.config_key("bulb").await {
-                BulbControlKind::Standard(toggle)
-            } else if let Ok(radio) = camera.config_key("eosremoterelease").await {
-                BulbControlKind::EosRemoteRelease(radio)
-            } else {
-                eyre::bail!("Camera does not support bulb exposures")
-            },
-            camera: camera.clone(),
+            camera: Arc::clone(camera),
         })
     }
 
     async fn toggle(&self, on: bool) -> eyre::Result<()> {
-        self.camera
-            .set_config(match &self.kind {
-                BulbControlKind::Standard(toggle) => {
-                    toggle.set_toggled(on);
-                    toggle
-                }
-                BulbControlKind::EosRemoteRelease(radio) => {
-                    radio.set_choice(if on { "Immediate" } else { "Release Full" })?;
-                    radio
-                }
-            })
-            .await?;
+        self.camera.set_bulb(on).await
+    }
 
-        Ok(())
+    /// Release bulb, blocking the current thread until the camera responds.
+    ///
+    /// For places where we can't await, like dropping an exposure on panic or shutdown.
+    fn release_blocking(&self) -> eyre::Result<()> {
+        self.camera.set_bulb_blocking(false)
+    }
+
+    /// Release bulb in case it's still held, e.g. by a previous session that crashed mid-exposure.
+    pub async fn release(&self) -> eyre::Result<()> {
+        self.toggle(false).await
     }
 
     pub async fn start(self) -> eyre::Result<BulbExposure> {
         self.toggle(true).await?;
-        Ok(BulbExposure { bulb: self })
+        Ok(BulbExposure { bulb: Some(self) })
     }
 }
 
+/// Guard for a held bulb that releases it when dropped, so that the shutter doesn't stay open
+/// if the exposure task errors out, panics or gets cancelled on shutdown.
 pub(crate) struct BulbExposure {
-    bulb: BulbControl,
+    /// Bulb control until it has been released.
+    bulb: Option<BulbControl>,
 }
 
 impl BulbExposure {
-    // TODO: maybe also implement blocking Drop just in case?
-    pub async fn stop(self) -> eyre::Result<()> {
-        self.bulb.toggle(false).await
+    pub async fn stop(mut self) -> eyre::Result<()> {
+        if let Some(bulb) = &self.bulb {
+            // On failure, the bulb stays set so that `drop` tries again.
+            bulb.toggle(false).await?;
+            self.bulb = None;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BulbExposure {
+    fn drop(&mut self) {
+        if let Some(bulb) = self.bulb.take() {
+            tracing::warn!("Bulb exposure wasn't stopped normally, releasing");
+            if let Err(err) = bulb.release_blocking() {
+                tracing::error!("Couldn't release bulb: {err:#}");
+            }
+        }
     }
 }
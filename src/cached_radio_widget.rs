@@ -1,41 +1,50 @@
-use super::convert_err;
+use crate::backend::CameraBackend;
 use ascom_alpaca::{ASCOMError, ASCOMResult};
-use gphoto2::widget::{RadioWidget, Widget};
-use std::ops::Deref;
 
-/// A wrapper around RadioWidget that doesn't re-read the list of choices on each get/set.
+/// A radio config widget with choices read once on connection.
+///
+/// The selected value is only stored locally until it's applied to the camera.
 #[derive(Debug)]
 pub(crate) struct CachedRadioWidget {
-    inner: RadioWidget,
+    name: String,
     choices: Vec<String>,
+    choice: parking_lot::Mutex<String>,
 }
 
-impl From<RadioWidget> for CachedRadioWidget {
-    fn from(inner: RadioWidget) -> Self {
-        Self {
-            choices: inner.choices_iter().collect(),
-            inner,
-        }
+impl CachedRadioWidget {
+    pub async fn load(camera: &dyn CameraBackend, name: &str) -> eyre::Result<Self> {
+        let config = camera.radio_config(name).await?;
+        Ok(Self {
+            name: name.to_owned(),
+            choices: config.choices,
+            choice: parking_lot::Mutex::new(config.current),
+        })
     }
-}
 
-impl TryFrom<Widget> for CachedRadioWidget {
-    type Error = gphoto2::Error;
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-    fn try_from(widget: Widget) -> Result<Self, Self::Error> {
-        RadioWidget::try_from(widget).map(Self::from)
+    pub fn choice(&self) -> String {
+        self.choice.lock().clone()
     }
-}
 
-impl Deref for CachedRadioWidget {
-    type Target = RadioWidget;
+    pub fn set_choice(&self, value: &str) -> eyre::Result<()> {
+        eyre::ensure!(
+            self.choices.iter().any(|choice| choice == value),
+            "Value {value:?} for {} is not one of the supported choices: {:?}",
+            self.name,
+            self.choices
+        );
+        *self.choice.lock() = value.to_owned();
+        Ok(())
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+    /// Send the selected value to the camera.
+    pub async fn apply(&self, camera: &dyn CameraBackend) -> eyre::Result<()> {
+        camera.set_radio_config(&self.name, &self.choice()).await
     }
-}
 
-impl CachedRadioWidget {
     pub fn choice_idx(&self) -> ASCOMResult<i32> {
         let choice_name = self.choice();
 
@@ -55,7 +64,8 @@ impl CachedRadioWidget {
             .choices
             .get(value as usize)
             .ok_or_else(|| ASCOMError::invalid_value("choice index out of range"))?;
-        self.inner.set_choice(choice_name).map_err(convert_err)
+        *self.choice.lock() = choice_name.clone();
+        Ok(())
     }
 
     pub fn choices(&self) -> &[String] {
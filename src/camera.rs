@@ -0,0 +1,430 @@
+use crate::archive::Archive;
+use crate::backend::CameraBackend;
+use crate::binning;
+use crate::binning::{BayerBinning, BinningMode};
+use crate::bulb_control::BulbControl;
+use crate::cached_radio_widget::CachedRadioWidget;
+use crate::capture::determine_dimensions;
+use crate::demosaic::DemosaicAlgorithm;
+use crate::device::CameraIdentity;
+use crate::dimensions::KnownDimensions;
+use crate::export::SaveFormat;
+use crate::fits::ExposureMetadata;
+use crate::parse_image::{bayer_offset, RawLevels};
+use crate::sensors::SensorInfo;
+use crate::shutter_speed::ShutterSpeeds;
+use ascom_alpaca::api::{CameraState, ImageArray, SensorType};
+use ascom_alpaca::{ASCOMError, ASCOMErrorCode, ASCOMResult};
+use atomic::{Atomic, Ordering};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{oneshot, watch, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Size {
+    pub width: u32,
+    pub height: u32,
+}
+
+pub(crate) struct StopExposure {
+    pub want_image: bool,
+}
+
+pub(crate) struct CurrentExposure {
+    pub rough_start: Instant,
+    pub state: Arc<Atomic<CameraState>>,
+    pub expected_duration: Duration,
+    pub stop_tx: Option<oneshot::Sender<StopExposure>>,
+    pub done_rx: watch::Receiver<bool>,
+}
+
+pub(crate) struct SuccessfulExposure {
+    pub image: ImageArray,
+    pub metadata: ExposureMetadata,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Temperature {
+    pub celsius: f64,
+    pub measured_at: Instant,
+}
+
+pub(crate) enum State {
+    Idle,
+    InExposure(CurrentExposure),
+    AfterExposure(ASCOMResult<SuccessfulExposure>),
+}
+
+pub(crate) struct MyCamera {
+    /// Handle to the camera, or `None` once released after losing connection to it.
+    pub backend: Option<Arc<dyn CameraBackend>>,
+    pub state: Arc<Mutex<State>>,
+    /// Sensor dimensions, or `None` if we don't know them until the first exposure.
+    pub dimensions: Arc<parking_lot::RwLock<Option<Size>>>,
+    pub iso: CachedRadioWidget,
+    /// Holds a handle to the camera too, so it's released along with `backend`.
+    pub bulb: Option<BulbControl>,
+    /// Discrete shutter speeds for timed exposures, or `None` if only bulb is available.
+    pub shutter_speeds: Option<ShutterSpeeds>,
+    /// Exposures at least this long use bulb unless they match a discrete shutter speed.
+    pub min_bulb_duration: Duration,
+    pub image_format: CachedRadioWidget,
+    pub last_exposure_start_time: Arc<Atomic<Option<SystemTime>>>,
+    pub last_exposure_duration: Arc<Atomic<Option<f64>>>,
+    /// JPEG written alongside the last image, or the image itself if it was a JPEG.
+    pub last_preview: Arc<parking_lot::Mutex<Option<Bytes>>>,
+    pub keep_on_card: bool,
+    pub archive: Option<Arc<Archive>>,
+    /// Where images are saved, using the archive filename template.
+    pub save_archive: Option<Arc<Archive>>,
+    pub auto_save: bool,
+    pub save_format: SaveFormat,
+    pub xisf_compression: bool,
+    /// Target name set by the client for archived filenames.
+    pub target: parking_lot::Mutex<Option<String>>,
+    /// Bayer pattern at the origin of the sensor's crop area, as seen in the last RAW frame.
+    pub cfa: Arc<parking_lot::RwLock<Option<rawler::CFA>>>,
+    pub sensor: SensorInfo,
+    pub last_temperature: Arc<parking_lot::Mutex<Option<Temperature>>>,
+    pub temperature_max_age: Duration,
+    /// Maximum pixel value of the last image.
+    pub max_adu: Arc<AtomicU32>,
+    /// Saturation level of the last image above its black level.
+    pub max_signal: Arc<AtomicU32>,
+    /// Electrons per ADU in the last image relative to the sensor's native gain,
+    /// accounting for rescaling and binning.
+    pub gain_scale: Arc<Atomic<f64>>,
+    pub raw_levels: RawLevels,
+    /// Whether RAW frames are demosaiced into colour images.
+    pub debayer: AtomicBool,
+    pub demosaic: DemosaicAlgorithm,
+    pub white_balance: bool,
+    /// Binning factor, same in both directions.
+    pub bin: AtomicU8,
+    pub binning_mode: BinningMode,
+    pub bayer_binning: BayerBinning,
+    /// Subframe set by the client in binned pixels, or `None` for the full frame.
+    pub subframe: parking_lot::RwLock<Option<image::math::Rect>>,
+    /// Set when we've lost connection to the camera and haven't managed to restore it yet.
+    pub link_lost: AtomicBool,
+}
+
+impl std::fmt::Debug for MyCamera {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MyCamera").finish_non_exhaustive()
+    }
+}
+
+#[tracing::instrument(skip(camera, widget), fields(widget = %widget.name()), err)]
+pub(crate) async fn apply_choice(
+    camera: &dyn CameraBackend,
+    widget: &CachedRadioWidget,
+    value: &str,
+) -> eyre::Result<()> {
+    widget.set_choice(value)?;
+    widget.apply(camera).await
+}
+
+pub(crate) async fn image_format_widget(
+    camera: &dyn CameraBackend,
+) -> eyre::Result<CachedRadioWidget> {
+    match CachedRadioWidget::load(camera, "imageformat").await {
+        Ok(widget) => Ok(widget),
+        Err(_) => CachedRadioWidget::load(camera, "imagequality").await,
+    }
+}
+
+/// Make sure bulb isn't left held from before we connected, e.g. by a crashed session.
+pub(crate) async fn release_bulb(bulb: BulbControl) -> BulbControl {
+    if let Err(err) = bulb.release().await {
+        tracing::warn!("Couldn't release bulb on connection: {err:#}");
+    }
+    bulb
+}
+
+impl MyCamera {
+    pub async fn new(
+        camera: Arc<dyn CameraBackend>,
+        identity: &CameraIdentity,
+        known_dimensions: &KnownDimensions,
+    ) -> eyre::Result<Self> {
+        let config = &identity.config;
+
+        let iso = CachedRadioWidget::load(&*camera, "iso").await?;
+        let image_format = image_format_widget(&*camera).await?;
+
+        for (widget, value) in [(&iso, &config.iso), (&image_format, &config.image_format)] {
+            if let Some(value) = value {
+                apply_choice(&*camera, widget, value).await?;
+            }
+        }
+
+        let keep_on_card = config.keep_on_card.unwrap_or(false);
+
+        let dimensions = match known_dimensions
+            .get(&identity.model)
+            .or_else(|| identity.sensor.dimensions())
+        {
+            Some(dimensions) => Some(dimensions),
+            None if config.test_exposure.unwrap_or(false) => {
+                let dimensions =
+                    determine_dimensions(&*camera, &image_format.choice(), keep_on_card).await?;
+                known_dimensions.observe(&identity.model, dimensions)?;
+                Some(dimensions)
+            }
+            None => {
+                tracing::warn!(
+                    "Sensor dimensions are unknown until the first exposure; \
+                    enable `test_exposure` in the config to determine them on connection instead"
+                );
+                None
+            }
+        };
+
+        Ok(Self {
+            iso,
+            bulb: Some(release_bulb(BulbControl::new(&camera)?).await),
+            shutter_speeds: ShutterSpeeds::new(&*camera).await,
+            min_bulb_duration: Duration::from_secs_f64(config.min_bulb_duration.unwrap_or(1.)),
+            image_format,
+            dimensions: Arc::new(parking_lot::RwLock::new(dimensions)),
+            backend: Some(camera),
+            state: Arc::new(Mutex::new(State::Idle)),
+            last_exposure_start_time: Default::default(),
+            last_exposure_duration: Default::default(),
+            last_preview: Default::default(),
+            keep_on_card,
+            archive: config
+                .archive_dir
+                .as_deref()
+                .map(|dir| Arc::new(Archive::new(dir, config))),
+            save_archive: config
+                .save_dir
+                .as_deref()
+                .map(|dir| Arc::new(Archive::new(dir, config))),
+            auto_save: config.auto_save.unwrap_or(true),
+            save_format: config.save_format.unwrap_or_default(),
+            xisf_compression: config.xisf_compression.unwrap_or(false),
+            target: Default::default(),
+            cfa: Default::default(),
+            sensor: identity.sensor.clone(),
+            last_temperature: Default::default(),
+            temperature_max_age: Duration::from_secs_f64(
+                config.temperature_max_age.unwrap_or(10. * 60.),
+            ),
+            max_adu: Arc::new(AtomicU32::new(u16::MAX.into())),
+            max_signal: Arc::new(AtomicU32::new(u16::MAX.into())),
+            gain_scale: Arc::new(Atomic::new(1.)),
+            raw_levels: config.raw_levels.unwrap_or_default(),
+            debayer: AtomicBool::new(false),
+            demosaic: config.demosaic.unwrap_or_default(),
+            white_balance: config.white_balance.unwrap_or(true),
+            bin: AtomicU8::new(1),
+            binning_mode: config.binning_mode.unwrap_or_default(),
+            bayer_binning: config.bayer_binning.unwrap_or_default(),
+            subframe: Default::default(),
+            link_lost: AtomicBool::new(false),
+        })
+    }
+
+    /// Switch over to a freshly opened handle for the same camera, restoring current settings.
+    ///
+    /// Unlike [`MyCamera::new`], this doesn't need to determine dimensions again.
+    pub async fn reopen(&mut self, camera: Arc<dyn CameraBackend>) -> eyre::Result<()> {
+        let iso = CachedRadioWidget::load(&*camera, "iso").await?;
+        apply_choice(&*camera, &iso, &self.iso.choice()).await?;
+
+        let image_format = image_format_widget(&*camera).await?;
+        apply_choice(&*camera, &image_format, &self.image_format.choice()).await?;
+
+        self.bulb = Some(release_bulb(BulbControl::new(&camera)?).await);
+        self.shutter_speeds = ShutterSpeeds::new(&*camera).await;
+        self.iso = iso;
+        self.image_format = image_format;
+        self.backend = Some(camera);
+        *self.link_lost.get_mut() = false;
+
+        Ok(())
+    }
+
+    /// Drop our handles to the camera; as long as any is alive, libgphoto2 keeps the USB
+    /// interface claimed and the camera can't be opened again.
+    pub fn release(&mut self) {
+        self.backend = None;
+        self.bulb = None;
+    }
+
+    pub fn backend(&self) -> ASCOMResult<&Arc<dyn CameraBackend>> {
+        self.backend.as_ref().ok_or(ASCOMError::NOT_CONNECTED)
+    }
+
+    pub async fn state(&self) -> tokio::sync::MutexGuard<'_, State> {
+        self.state.lock().await
+    }
+
+    pub fn dimensions(&self) -> ASCOMResult<Size> {
+        self.dimensions.read().ok_or_else(|| {
+            ASCOMError::new(
+                ASCOMErrorCode::VALUE_NOT_SET,
+                "Sensor dimensions are not known yet, take an exposure first",
+            )
+        })
+    }
+
+    pub fn subframe(&self) -> ASCOMResult<image::math::Rect> {
+        if let Some(subframe) = *self.subframe.read() {
+            return Ok(subframe);
+        }
+        let dimensions = self.dimensions()?;
+        let bin = self.bin();
+        Ok(image::math::Rect {
+            x: 0,
+            y: 0,
+            width: dimensions.width / bin,
+            height: dimensions.height / bin,
+        })
+    }
+
+    pub fn sensor_type(&self) -> SensorType {
+        match is_raw_format(&self.image_format.choice()) {
+            true if self.debayer.load(Ordering::Relaxed) => SensorType::Color,
+            // Binned RAW frames are combined into superpixels, so there's no Bayer pattern left.
+            true if self.bin() > 1 => match self.bayer_binning {
+                BayerBinning::Color => SensorType::Color,
+                BayerBinning::Mono => SensorType::Monochrome,
+            },
+            true => SensorType::RGGB,
+            false => SensorType::Color,
+        }
+    }
+
+    /// Bayer offset of the image that will be returned for the current subframe.
+    pub fn bayer_offset(&self) -> ASCOMResult<(u32, u32)> {
+        if self.sensor_type() != SensorType::RGGB {
+            return Err(ASCOMError::NOT_IMPLEMENTED);
+        }
+        let subframe = self.subframe()?;
+        let cfa = self.cfa.read();
+        let cfa = cfa.as_ref().ok_or_else(|| {
+            ASCOMError::new(
+                ASCOMErrorCode::VALUE_NOT_SET,
+                "Bayer pattern is not known yet, take a RAW exposure first",
+            )
+        })?;
+        Ok(
+            bayer_offset(&cfa.shift(subframe.x as usize, subframe.y as usize))
+                .expect("unsupported Bayer patterns are rejected on parsing"),
+        )
+    }
+
+    /// Gain in e-/ADU at the current ISO, if known.
+    pub fn electrons_per_adu(&self) -> Option<f64> {
+        let gain = self.sensor.at_iso(&self.iso.choice()).gain?;
+        Some(gain * self.gain_scale.load(Ordering::Relaxed))
+    }
+
+    /// Readout modes as pairs of image format choice index and whether to debayer.
+    pub fn readout_modes(&self) -> Vec<(usize, bool)> {
+        self.image_format
+            .choices()
+            .iter()
+            .enumerate()
+            .flat_map(|(index, choice)| {
+                std::iter::once((index, false))
+                    .chain(is_raw_format(choice).then_some((index, true)))
+            })
+            .collect()
+    }
+
+    pub fn pixel_size(&self) -> ASCOMResult<f64> {
+        self.sensor.pixel_size.ok_or_else(|| {
+            ASCOMError::new(
+                ASCOMErrorCode::VALUE_NOT_SET,
+                "Pixel size of this sensor is not known; add it to the `sensors` section of the config",
+            )
+        })
+    }
+
+    /// Shortest exposure in seconds we can take.
+    pub fn shortest_exposure(&self) -> f64 {
+        match &self.shutter_speeds {
+            Some(shutter_speeds) => shutter_speeds.shortest().as_secs_f64(),
+            // Considering that bulb needs some high-latency operations,
+            // we can't go very low in terms of precision.
+            None => 0.1,
+        }
+    }
+
+    pub fn bin(&self) -> u32 {
+        self.bin.load(Ordering::Relaxed).into()
+    }
+
+    pub fn set_bin(&self, bin: i32) -> ASCOMResult {
+        match u8::try_from(bin) {
+            Ok(bin @ 1..=binning::MAX_BIN) => {
+                self.bin.store(bin, Ordering::Relaxed);
+                Ok(())
+            }
+            _ => Err(ASCOMError::invalid_value(format_args!(
+                "Binning must be between 1 and {}",
+                binning::MAX_BIN
+            ))),
+        }
+    }
+
+    /// Set one side of the subframe; the whole rectangle is checked against the sensor when
+    /// an exposure starts, as binning may still change until then.
+    pub fn update_subframe(
+        &self,
+        value: i32,
+        side: impl FnOnce(&mut image::math::Rect) -> &mut u32,
+    ) -> ASCOMResult {
+        let value = u32::try_from(value)
+            .map_err(|_| ASCOMError::invalid_value("Subframe values must be non-negative"))?;
+        let subframe = self.subframe()?;
+        *side(self.subframe.write().get_or_insert(subframe)) = value;
+        Ok(())
+    }
+
+    /// Check that the subframe is non-empty and fits on the sensor at the current binning.
+    pub fn validate_subframe(&self) -> ASCOMResult {
+        let Some(subframe) = *self.subframe.read() else {
+            return Ok(());
+        };
+        let dimensions = self.dimensions()?;
+        let bin = self.bin();
+        for (axis, start, len, max_len) in [
+            ("X", subframe.x, subframe.width, dimensions.width / bin),
+            ("Y", subframe.y, subframe.height, dimensions.height / bin),
+        ] {
+            if len == 0 {
+                return Err(ASCOMError::invalid_value(format_args!(
+                    "Num{axis} must be positive"
+                )));
+            }
+            if start.checked_add(len).map_or(true, |end| end > max_len) {
+                return Err(ASCOMError::invalid_value(format_args!(
+                    "Subframe Start{axis} {start} + Num{axis} {len} exceeds {max_len} pixels at {bin}x{bin} binning"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn is_raw_format(image_format: &str) -> bool {
+    // Little crude but seems to match usual gphoto2 RAW names in settinngs.
+    image_format.contains("RAW") || image_format.contains("NEF")
+}
+
+/// Number of files the camera writes per capture in the given image format,
+/// e.g. "RAW + Large Fine JPEG" or "NEF+Fine" produce both RAW and JPEG.
+pub(crate) fn files_per_capture(image_format: &str) -> usize {
+    match is_raw_format(image_format) && image_format.contains('+') {
+        true => 2,
+        false => 1,
+    }
+}
@@ -0,0 +1,184 @@
+use crate::archive::{Archive, FrameInfo};
+use crate::backend::{CameraBackend, CameraEvent, CameraFile};
+use crate::bulb_control::BulbControl;
+use crate::camera::{files_per_capture, Size};
+use crate::config::PendingFiles;
+use crate::error::{convert_err, DriverError};
+use crate::parse_image::ImgWithMetadata;
+use ascom_alpaca::api::CameraState;
+use ascom_alpaca::{ASCOMError, ASCOMResult};
+use atomic::{Atomic, Ordering};
+use bytes::Bytes;
+use std::time::{Duration, Instant};
+use tokio::select;
+use tokio::time::sleep;
+
+pub(crate) fn is_jpeg_file(file: &CameraFile) -> bool {
+    let name = file.name.to_ascii_lowercase();
+    name.ends_with(".jpg") || name.ends_with(".jpeg")
+}
+
+/// Download files of a single capture, preferring RAW over JPEG when the camera writes both.
+///
+/// Returns data of the file to read the image from, and of a JPEG for previews if there's one.
+/// Originals are saved to `archive` if given, and deleted from the card unless `keep_on_card`
+/// is set or archiving them failed.
+pub(crate) async fn download_capture(
+    camera: &dyn CameraBackend,
+    mut files: Vec<CameraFile>,
+    keep_on_card: bool,
+    archive: Option<(&Archive, &FrameInfo)>,
+) -> eyre::Result<(Bytes, Option<Bytes>)> {
+    files.sort_by_key(is_jpeg_file);
+    let mut main = None;
+    let mut preview = None;
+    for file in files {
+        let is_jpeg = is_jpeg_file(&file);
+        let wanted = main.is_none() || (is_jpeg && preview.is_none());
+        if !wanted && archive.is_none() {
+            if !keep_on_card {
+                tracing::debug!(file = ?file.name, "Deleting extra file of the capture");
+                camera.delete(&file).await?;
+            }
+            continue;
+        }
+
+        let data = camera.download(&file).await?;
+
+        let mut keep = keep_on_card;
+        if let Some((archive, frame)) = archive {
+            match archive.save(frame, &file.name, &data) {
+                Ok(archived) => tracing::info!(?archived, "Archived original file"),
+                Err(err) => {
+                    tracing::warn!(
+                        "Couldn't archive {}, keeping it on the camera: {err:#}",
+                        file.name
+                    );
+                    keep = true;
+                }
+            }
+        }
+        if !keep {
+            camera.delete(&file).await?;
+        }
+
+        if main.is_none() {
+            if is_jpeg {
+                preview = Some(data.clone());
+            }
+            main = Some(data);
+        } else if wanted {
+            preview = Some(data);
+        }
+    }
+    let main = main.ok_or_else(|| {
+        eyre::Report::new(DriverError::CameraFailure).wrap_err("Capture didn't produce any files")
+    })?;
+    Ok((main, preview))
+}
+
+/// Collect files reported by the camera until `expected` of them arrive or it goes quiet.
+pub(crate) async fn wait_for_files(
+    camera: &dyn CameraBackend,
+    files: &mut Vec<CameraFile>,
+    expected: usize,
+) -> ASCOMResult {
+    while files.len() < expected {
+        match camera
+            .wait_event(std::time::Duration::from_secs(3))
+            .await
+            .map_err(convert_err)?
+        {
+            CameraEvent::NewFile(new_file) => files.push(new_file),
+            CameraEvent::Timeout => break,
+            e => {
+                tracing::trace!(event = ?e, "Ignoring event while waiting for exposure completion")
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tracing::instrument(skip(camera), ret, err)]
+pub(crate) async fn determine_dimensions(
+    camera: &dyn CameraBackend,
+    image_format: &str,
+    keep_on_card: bool,
+) -> eyre::Result<Size> {
+    let mut files = vec![camera.capture().await?];
+    wait_for_files(camera, &mut files, files_per_capture(image_format)).await?;
+    let (data, _) = download_capture(camera, files, keep_on_card, None).await?;
+
+    let rect = ImgWithMetadata::from_data(data)?.crop_area;
+
+    Ok(Size {
+        width: rect.width,
+        height: rect.height,
+    })
+}
+
+/// Hold the shutter open via bulb control for `hold`, then wait for the camera to report
+/// `expected_files` new files.
+///
+/// `stop` resolves early with whether to keep the image. Returns the files and how long bulb was held.
+pub(crate) async fn bulb_capture(
+    camera: &dyn CameraBackend,
+    bulb: BulbControl,
+    hold: Duration,
+    expected_files: usize,
+    stop: impl std::future::Future<Output = bool>,
+    exposing_state: &Atomic<CameraState>,
+) -> ASCOMResult<(Vec<CameraFile>, Duration)> {
+    let bulb_exposure = bulb.start().await.map_err(convert_err)?;
+    exposing_state.store(CameraState::Exposing, Ordering::Relaxed);
+    let start_instant = Instant::now();
+    let want_image = select! {
+        _ = sleep(hold) => true,
+        want_image = stop => want_image,
+    };
+    let held = start_instant.elapsed();
+    bulb_exposure.stop().await.map_err(convert_err)?;
+
+    if !want_image {
+        return Err(ASCOMError::invalid_operation("Exposure was aborted"));
+    }
+
+    exposing_state.store(CameraState::Reading, Ordering::Relaxed);
+
+    let mut files = Vec::with_capacity(expected_files);
+    wait_for_files(camera, &mut files, expected_files).await?;
+
+    if files.is_empty() {
+        return Err(ASCOMError::new(
+            DriverError::CameraFailure.code(),
+            "Capture finished but didn't find file path",
+        ));
+    }
+    Ok((files, held))
+}
+
+/// Delete or keep files the camera has reported since the last exposure, e.g. from an aborted one.
+pub(crate) async fn handle_pending_files(
+    camera: &dyn CameraBackend,
+    policy: PendingFiles,
+) -> eyre::Result<()> {
+    loop {
+        match camera.wait_event(Duration::from_secs(1)).await? {
+            CameraEvent::NewFile(file) => {
+                let folder = &file.folder;
+                let filename = &file.name;
+                match policy {
+                    PendingFiles::Delete => {
+                        tracing::info!(?folder, ?filename, "Deleting file left on the camera");
+                        camera.delete(&file).await?;
+                    }
+                    PendingFiles::Keep => {
+                        tracing::info!(?folder, ?filename, "Keeping file on the camera");
+                    }
+                }
+            }
+            CameraEvent::Timeout => return Ok(()),
+            _ => {}
+        }
+    }
+}
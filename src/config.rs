@@ -0,0 +1,487 @@
+use crate::archive;
+use crate::binning::{BayerBinning, BinningMode};
+use crate::demosaic::DemosaicAlgorithm;
+use crate::export::SaveFormat;
+use crate::parse_image::RawLevels;
+use crate::sensors::SensorInfo;
+use clap::Parser;
+use eyre::Context;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+
+/// ASCOM Alpaca server for DSLR and mirrorless cameras.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Path to the TOML configuration file.
+    #[arg(short, long, env = "ALPACA_DSLR_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// IP address to listen on (overrides `server.listen_addr`).
+    #[arg(long, env = "ALPACA_DSLR_LISTEN_ADDR")]
+    listen_addr: Option<IpAddr>,
+
+    /// Port to listen on (overrides `server.port`).
+    #[arg(short, long, env = "ALPACA_DSLR_PORT")]
+    port: Option<u16>,
+
+    /// Directory for persistent driver state (overrides `state_dir`).
+    #[arg(long, env = "ALPACA_DSLR_STATE_DIR")]
+    state_dir: Option<PathBuf>,
+
+    /// Log filter in the `tracing` directive syntax (overrides `log.filter`).
+    #[arg(long, env = "RUST_LOG")]
+    log_filter: Option<String>,
+
+    /// Only register cameras whose model contains this string (adds to `cameras.include`).
+    #[arg(long = "camera")]
+    cameras: Vec<String>,
+
+    /// Number of simulated cameras to register (overrides `simulator.cameras`).
+    #[arg(long, env = "ALPACA_DSLR_SIMULATOR")]
+    simulator: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServerConfig {
+    pub listen_addr: IpAddr,
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: Ipv4Addr::LOCALHOST.into(),
+            port: 3000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LogConfig {
+    pub filter: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: "info".to_owned(),
+        }
+    }
+}
+
+/// What to do with an exposure in progress on shutdown.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum InFlightExposure {
+    #[default]
+    Abort,
+    /// Let the exposure complete, aborting it only if it doesn't within the shutdown timeout.
+    Finish,
+}
+
+/// What to do on shutdown with files the camera has captured but we haven't downloaded,
+/// e.g. from an aborted exposure.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PendingFiles {
+    #[default]
+    Delete,
+    Keep,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ShutdownConfig {
+    pub exposure: InFlightExposure,
+    pub pending_files: PendingFiles,
+    /// How long to wait for each step of the in-flight exposure handling, in seconds.
+    pub timeout: f64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            exposure: InFlightExposure::default(),
+            pending_files: PendingFiles::default(),
+            timeout: 60.,
+        }
+    }
+}
+
+/// Settings applied to the camera on connection.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CameraConfig {
+    /// Initial ISO choice, as listed by gphoto2 (e.g. "800").
+    pub iso: Option<String>,
+    /// Initial image format choice, as listed by gphoto2 (e.g. "RAW").
+    pub image_format: Option<String>,
+    /// Take a test exposure on connection if sensor dimensions are not known from
+    /// the built-in database or previous sessions.
+    pub test_exposure: Option<bool>,
+    /// How pixels are combined when binning: "sum" or "average".
+    pub binning_mode: Option<BinningMode>,
+    /// What binning RAW frames produces: "mono" or "color" superpixels.
+    pub bayer_binning: Option<BayerBinning>,
+    /// Demosaic algorithm for debayered RAW readout modes: "bilinear" or "vng".
+    pub demosaic: Option<DemosaicAlgorithm>,
+    /// Apply the as-shot white balance in debayered RAW readout modes.
+    pub white_balance: Option<bool>,
+    /// Black and white level handling for RAW frames: "native", "subtract_black" or "rescale".
+    pub raw_levels: Option<RawLevels>,
+    /// How long a camera temperature reading stays valid, in seconds.
+    pub temperature_max_age: Option<f64>,
+    /// Exposures shorter than this many seconds snap to the closest discrete shutter speed
+    /// instead of using bulb, where USB latency would make timing inaccurate.
+    pub min_bulb_duration: Option<f64>,
+    /// Leave original files on the camera's card after download.
+    pub keep_on_card: Option<bool>,
+    /// Directory to save original files to.
+    pub archive_dir: Option<PathBuf>,
+    /// Path of archived files relative to `archive_dir`; see the README for placeholders.
+    pub archive_template: Option<String>,
+    /// Skip archiving when the disk would have less than this many megabytes left.
+    pub archive_min_free_space: Option<u64>,
+    /// Directory to save images to, named by `archive_template`.
+    pub save_dir: Option<PathBuf>,
+    /// Save every image to `save_dir` rather than only on request via the `SaveImage` action.
+    pub auto_save: Option<bool>,
+    pub save_format: Option<SaveFormat>,
+    /// Compress XISF images with zlib.
+    pub xisf_compression: Option<bool>,
+}
+
+impl CameraConfig {
+    /// Fill in any unset fields from `defaults`.
+    fn or(self, defaults: &CameraConfig) -> Self {
+        Self {
+            iso: self.iso.or_else(|| defaults.iso.clone()),
+            image_format: self.image_format.or_else(|| defaults.image_format.clone()),
+            test_exposure: self.test_exposure.or(defaults.test_exposure),
+            binning_mode: self.binning_mode.or(defaults.binning_mode),
+            bayer_binning: self.bayer_binning.or(defaults.bayer_binning),
+            demosaic: self.demosaic.or(defaults.demosaic),
+            white_balance: self.white_balance.or(defaults.white_balance),
+            raw_levels: self.raw_levels.or(defaults.raw_levels),
+            temperature_max_age: self.temperature_max_age.or(defaults.temperature_max_age),
+            min_bulb_duration: self.min_bulb_duration.or(defaults.min_bulb_duration),
+            keep_on_card: self.keep_on_card.or(defaults.keep_on_card),
+            archive_dir: self.archive_dir.or_else(|| defaults.archive_dir.clone()),
+            archive_template: self
+                .archive_template
+                .or_else(|| defaults.archive_template.clone()),
+            archive_min_free_space: self
+                .archive_min_free_space
+                .or(defaults.archive_min_free_space),
+            save_dir: self.save_dir.or_else(|| defaults.save_dir.clone()),
+            auto_save: self.auto_save.or(defaults.auto_save),
+            save_format: self.save_format.or(defaults.save_format),
+            xisf_compression: self.xisf_compression.or(defaults.xisf_compression),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CamerasConfig {
+    /// Substrings of camera models to register; all cameras are registered if empty.
+    pub include: Vec<String>,
+    /// Substrings of camera models to skip, even if they match `include`.
+    pub exclude: Vec<String>,
+    /// Settings applied to all cameras.
+    pub defaults: CameraConfig,
+    /// Per-model settings, keyed by the exact model name as reported by gphoto2.
+    pub models: BTreeMap<String, CameraConfig>,
+    /// Number of extra device slots to register for cameras attached after startup.
+    pub spare_slots: usize,
+    /// How often to rescan for attached and detached cameras, in seconds; 0 disables rescanning.
+    pub rescan_interval: f64,
+}
+
+impl Default for CamerasConfig {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            defaults: CameraConfig::default(),
+            models: BTreeMap::new(),
+            spare_slots: 1,
+            rescan_interval: 5.,
+        }
+    }
+}
+
+impl CamerasConfig {
+    pub fn should_register(&self, model: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|s| model.contains(s.as_str())))
+            && !self.exclude.iter().any(|s| model.contains(s.as_str()))
+    }
+
+    pub fn for_model(&self, model: &str) -> CameraConfig {
+        self.models
+            .get(model)
+            .cloned()
+            .unwrap_or_default()
+            .or(&self.defaults)
+    }
+}
+
+/// Failure the simulator can inject into a capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SimulatedFailure {
+    /// The camera refuses the capture as busy.
+    Busy,
+    /// The camera doesn't respond.
+    Timeout,
+    /// The file gets corrupted on download.
+    Corrupted,
+    /// The camera drops off the bus, as if unplugged.
+    Disconnect,
+}
+
+/// Simulated cameras producing synthetic star field frames, for testing without hardware.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SimulatorConfig {
+    /// Number of simulated cameras to register alongside the real ones.
+    pub cameras: usize,
+    /// Model name reported by simulated cameras.
+    pub model: String,
+    pub width: u32,
+    pub height: u32,
+    /// Colour filter pattern of RAW frames: "RGGB", "BGGR", "GRBG" or "GBRG".
+    pub bayer_pattern: String,
+    /// Read noise in ADU at ISO 100.
+    pub read_noise: f64,
+    /// Sky background in ADU per second at ISO 100.
+    pub sky_background: f64,
+    /// Number of stars in the field.
+    pub stars: usize,
+    /// Delay of every camera operation in seconds, mimicking USB latency.
+    pub latency: f64,
+    /// Probability of each capture failing with one of `failures`.
+    pub failure_rate: f64,
+    pub failures: Vec<SimulatedFailure>,
+    /// Seed for noise and star positions, for reproducible frames.
+    pub seed: Option<u64>,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            cameras: 0,
+            model: "Simulated DSLR".to_owned(),
+            width: 1920,
+            height: 1280,
+            bayer_pattern: "RGGB".to_owned(),
+            read_noise: 3.,
+            sky_background: 20.,
+            stars: 50,
+            latency: 0.05,
+            failure_rate: 0.,
+            failures: vec![
+                SimulatedFailure::Busy,
+                SimulatedFailure::Timeout,
+                SimulatedFailure::Corrupted,
+                SimulatedFailure::Disconnect,
+            ],
+            seed: None,
+        }
+    }
+}
+
+/// Driver configuration; see the README for the file format.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Directory for data that should survive restarts, like assigned device IDs.
+    pub(crate) state_dir: Option<PathBuf>,
+    pub(crate) server: ServerConfig,
+    pub(crate) log: LogConfig,
+    pub(crate) cameras: CamerasConfig,
+    pub(crate) shutdown: ShutdownConfig,
+    pub(crate) simulator: SimulatorConfig,
+    /// Additions and overrides for the built-in sensor database, keyed by the exact model name.
+    pub(crate) sensors: BTreeMap<String, SensorInfo>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            state_dir: None,
+            server: Default::default(),
+            log: Default::default(),
+            cameras: Default::default(),
+            shutdown: Default::default(),
+            simulator: Default::default(),
+            sensors: Default::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Load configuration from the config file (if any), environment and command-line arguments.
+    ///
+    /// Precedence, from highest to lowest: command-line, environment, config file, defaults.
+    pub fn load() -> eyre::Result<Self> {
+        let args = Args::parse();
+
+        let mut config = match &args.config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .wrap_err_with(|| format!("Couldn't read config file {}", path.display()))?;
+                toml::from_str(&contents)
+                    .wrap_err_with(|| format!("Invalid config file {}", path.display()))?
+            }
+            None => Self::default(),
+        };
+
+        if let Some(listen_addr) = args.listen_addr {
+            config.server.listen_addr = listen_addr;
+        }
+        if let Some(port) = args.port {
+            config.server.port = port;
+        }
+        if let Some(state_dir) = args.state_dir {
+            config.state_dir = Some(state_dir);
+        }
+        if let Some(log_filter) = args.log_filter {
+            config.log.filter = log_filter;
+        }
+        config.cameras.include.extend(args.cameras);
+        if let Some(simulator) = args.simulator {
+            config.simulator.cameras = simulator;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// The configured state directory, or one in the platform-specific local data directory.
+    ///
+    /// There's no sensible fallback without either: a relative path would make device IDs
+    /// depend on the working directory.
+    pub(crate) fn state_dir(&self) -> eyre::Result<PathBuf> {
+        if let Some(state_dir) = &self.state_dir {
+            return Ok(state_dir.clone());
+        }
+        let data_dir = dirs::data_local_dir().ok_or_else(|| {
+            eyre::eyre!(
+                "Couldn't find the local data directory; set `state_dir` or pass --state-dir"
+            )
+        })?;
+        Ok(data_dir.join(env!("CARGO_PKG_NAME")))
+    }
+
+    /// Parse and validate configuration in the TOML file format, without looking at arguments.
+    pub fn parse(contents: &str) -> eyre::Result<Self> {
+        let config: Self = toml::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn log_filter(&self) -> &str {
+        &self.log.filter
+    }
+
+    fn validate(&self) -> eyre::Result<()> {
+        self.state_dir()?;
+
+        tracing_subscriber::EnvFilter::try_new(&self.log.filter)
+            .wrap_err_with(|| format!("Invalid log filter {:?}", self.log.filter))?;
+
+        for pattern in self.cameras.include.iter().chain(&self.cameras.exclude) {
+            eyre::ensure!(
+                !pattern.is_empty(),
+                "Camera include/exclude patterns must not be empty"
+            );
+        }
+
+        eyre::ensure!(
+            self.cameras.rescan_interval.is_finite() && self.cameras.rescan_interval >= 0.,
+            "Camera rescan interval must be a non-negative number of seconds"
+        );
+
+        eyre::ensure!(
+            self.shutdown.timeout.is_finite() && self.shutdown.timeout >= 0.,
+            "Shutdown timeout must be a non-negative number of seconds"
+        );
+
+        for (model, camera) in std::iter::once(("defaults", &self.cameras.defaults)).chain(
+            self.cameras
+                .models
+                .iter()
+                .map(|(model, camera)| (model.as_str(), camera)),
+        ) {
+            for (name, value) in [("iso", &camera.iso), ("image_format", &camera.image_format)] {
+                eyre::ensure!(
+                    value.as_deref() != Some(""),
+                    "Camera setting {name} for {model} must not be empty"
+                );
+            }
+            if let Some(max_age) = camera.temperature_max_age {
+                eyre::ensure!(
+                    max_age.is_finite() && max_age > 0.,
+                    "Camera temperature_max_age for {model} must be a positive number of seconds"
+                );
+            }
+            if let Some(min_bulb_duration) = camera.min_bulb_duration {
+                eyre::ensure!(
+                    min_bulb_duration.is_finite() && min_bulb_duration >= 0.,
+                    "Camera min_bulb_duration for {model} must be a non-negative number of seconds"
+                );
+            }
+            if let Some(template) = &camera.archive_template {
+                archive::validate_template(template)
+                    .wrap_err_with(|| format!("Invalid archive_template for {model}"))?;
+            }
+        }
+
+        let simulator = &self.simulator;
+        eyre::ensure!(
+            !simulator.model.is_empty(),
+            "Simulator model must not be empty"
+        );
+        eyre::ensure!(
+            simulator.width >= 2 && simulator.height >= 2,
+            "Simulator frame must be at least 2x2 pixels"
+        );
+        eyre::ensure!(
+            ["RGGB", "BGGR", "GRBG", "GBRG"].contains(&simulator.bayer_pattern.as_str()),
+            "Simulator bayer_pattern must be one of RGGB, BGGR, GRBG or GBRG"
+        );
+        for (name, value) in [
+            ("read_noise", simulator.read_noise),
+            ("sky_background", simulator.sky_background),
+            ("latency", simulator.latency),
+        ] {
+            eyre::ensure!(
+                value.is_finite() && value >= 0.,
+                "Simulator {name} must be a non-negative number"
+            );
+        }
+        eyre::ensure!(
+            (0. ..=1.).contains(&simulator.failure_rate),
+            "Simulator failure_rate must be between 0 and 1"
+        );
+        eyre::ensure!(
+            simulator.failure_rate == 0. || !simulator.failures.is_empty(),
+            "Simulator failures must not be empty when failure_rate is set"
+        );
+
+        for (model, sensor) in &self.sensors {
+            sensor
+                .validate()
+                .wrap_err_with(|| format!("Invalid sensor info for {model}"))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+use image::{DynamicImage, ImageBuffer, Luma, Rgb};
+use rawler::CFA;
+use serde::Deserialize;
+
+/// Algorithm used to interpolate missing colours in debayered readout modes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DemosaicAlgorithm {
+    /// Average of same-colour neighbours; fast, but blurs edges and produces colour fringes.
+    Bilinear,
+    /// Variable Number of Gradients; interpolates only along the smoothest directions.
+    #[default]
+    Vng,
+}
+
+/// Directions for VNG gradients, clockwise from north.
+const DIRECTIONS: [(i64, i64); 8] = [
+    (0, -1),
+    (1, -1),
+    (1, 0),
+    (1, 1),
+    (0, 1),
+    (-1, 1),
+    (-1, 0),
+    (-1, -1),
+];
+
+struct Mosaic<'a> {
+    img: &'a ImageBuffer<Luma<u16>, Vec<u16>>,
+    cfa: &'a CFA,
+}
+
+impl Mosaic<'_> {
+    /// Colour index and value of the pixel at the given position, if it's within bounds.
+    fn get(&self, x: i64, y: i64) -> Option<(usize, f32)> {
+        let (x, y) = (u32::try_from(x).ok()?, u32::try_from(y).ok()?);
+        if x >= self.img.width() || y >= self.img.height() {
+            return None;
+        }
+        Some((
+            self.cfa.color_at(y as usize, x as usize),
+            self.img.get_pixel(x, y).0[0].into(),
+        ))
+    }
+
+    fn value(&self, x: i64, y: i64) -> Option<f32> {
+        self.get(x, y).map(|(_, value)| value)
+    }
+
+    fn bilinear(&self, x: i64, y: i64) -> [f32; 3] {
+        let (center_color, center) = self.get(x, y).expect("pixel out of bounds");
+        let mut sums = [0_f32; 3];
+        let mut counts = [0_u32; 3];
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                if let Some((color @ 0..=2, value)) = self.get(x + dx, y + dy) {
+                    sums[color] += value;
+                    counts[color] += 1;
+                }
+            }
+        }
+        std::array::from_fn(|color| match counts[color] {
+            _ if color == center_color => center,
+            0 => 0.,
+            count => sums[color] / count as f32,
+        })
+    }
+
+    fn vng(&self, x: i64, y: i64) -> [f32; 3] {
+        let (center_color, center) = self.get(x, y).expect("pixel out of bounds");
+        let diff = |(ax, ay): (i64, i64), (bx, by): (i64, i64)| {
+            Some((self.value(x + ax, y + ay)? - self.value(x + bx, y + by)?).abs())
+        };
+
+        // Each term compares pixels of the same colour along the direction.
+        let gradients = DIRECTIONS.map(|(dx, dy)| {
+            let (px, py) = (-dy, dx);
+            Some(
+                diff((dx, dy), (-dx, -dy))?
+                    + diff((2 * dx, 2 * dy), (0, 0))?
+                    + (diff((dx + px, dy + py), (-dx + px, -dy + py))?
+                        + diff((dx - px, dy - py), (-dx - px, -dy - py))?)
+                        / 2.,
+            )
+        });
+
+        let (min, max) = gradients
+            .iter()
+            .flatten()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &g| {
+                (min.min(g), max.max(g))
+            });
+        if !min.is_finite() {
+            // Too close to the edge for gradients.
+            return self.bilinear(x, y);
+        }
+        let threshold = 1.5 * min + 0.5 * (max - min);
+
+        // Sum of colour differences from the centre colour over the selected directions.
+        let mut diff_sums = [0_f32; 3];
+        let mut diff_counts = [0_u32; 3];
+
+        for (&(dx, dy), gradient) in DIRECTIONS.iter().zip(gradients) {
+            if !matches!(gradient, Some(gradient) if gradient <= threshold) {
+                continue;
+            }
+            let (px, py) = (-dy, dx);
+            let mut sums = [0_f32; 3];
+            let mut counts = [0_u32; 3];
+            for (ox, oy) in [
+                (dx, dy),
+                (2 * dx, 2 * dy),
+                (dx + px, dy + py),
+                (dx - px, dy - py),
+            ] {
+                if let Some((color @ 0..=2, value)) = self.get(x + ox, y + oy) {
+                    sums[color] += value;
+                    counts[color] += 1;
+                }
+            }
+            if counts[center_color] == 0 {
+                continue;
+            }
+            let center_avg = sums[center_color] / counts[center_color] as f32;
+            for color in 0..3 {
+                if color != center_color && counts[color] > 0 {
+                    diff_sums[color] += sums[color] / counts[color] as f32 - center_avg;
+                    diff_counts[color] += 1;
+                }
+            }
+        }
+
+        let mut bilinear = None;
+        std::array::from_fn(|color| match diff_counts[color] {
+            _ if color == center_color => center,
+            // None of the smooth directions has this colour, fall back to plain interpolation.
+            0 => bilinear.get_or_insert_with(|| self.bilinear(x, y))[color],
+            count => (center + diff_sums[color] / count as f32).max(0.),
+        })
+    }
+}
+
+/// Demosaic a RAW frame into an RGB image.
+///
+/// `cfa` is the colour filter pattern at the image origin, and `white_balance` contains optional
+/// per-channel multipliers applied after interpolation to values above the `black` level.
+pub(crate) fn demosaic(
+    image: &DynamicImage,
+    cfa: &CFA,
+    algorithm: DemosaicAlgorithm,
+    white_balance: Option<[f32; 3]>,
+    black: f32,
+) -> eyre::Result<DynamicImage> {
+    let DynamicImage::ImageLuma16(img) = image else {
+        eyre::bail!("unsupported image colour format for demosaicing");
+    };
+    let mosaic = Mosaic { img, cfa };
+    let white_balance = white_balance.unwrap_or([1.; 3]);
+
+    let mut samples = Vec::with_capacity(img.width() as usize * img.height() as usize * 3);
+
+    for y in 0..i64::from(img.height()) {
+        for x in 0..i64::from(img.width()) {
+            let rgb = match algorithm {
+                DemosaicAlgorithm::Bilinear => mosaic.bilinear(x, y),
+                DemosaicAlgorithm::Vng => mosaic.vng(x, y),
+            };
+            samples.extend(rgb.iter().zip(white_balance).map(|(value, multiplier)| {
+                ((value - black).max(0.) * multiplier + black).round() as u16
+            }));
+        }
+    }
+
+    Ok(
+        ImageBuffer::<Rgb<u16>, _>::from_raw(img.width(), img.height(), samples)
+            .expect("demosaiced buffer size mismatch")
+            .into(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uniform_colour_is_restored() {
+        for pattern in ["RGGB", "BGGR", "GRBG", "GBRG"] {
+            let cfa = CFA::new(pattern);
+            let rgb = [400, 800, 200];
+            let image = ImageBuffer::from_fn(7, 5, |x, y| {
+                Luma([rgb[cfa.color_at(y as usize, x as usize)]])
+            })
+            .into();
+            for algorithm in [DemosaicAlgorithm::Bilinear, DemosaicAlgorithm::Vng] {
+                let result = demosaic(&image, &cfa, algorithm, None, 0.).unwrap();
+                assert!(
+                    result.as_rgb16().unwrap().pixels().all(|p| p.0 == rgb),
+                    "{algorithm:?} {pattern}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn vng_blurs_edges_less() {
+        // Grey image with a vertical edge, which bilinear interpolation smears across.
+        let image = ImageBuffer::from_fn(8, 8, |x, _| Luma([if x < 4 { 1000_u16 } else { 100 }]));
+        let image = DynamicImage::from(image);
+        let cfa = CFA::new("RGGB");
+
+        // Total error of the blue pixel at (3, 3), just left of the edge.
+        let error = |algorithm| -> i32 {
+            let result = demosaic(&image, &cfa, algorithm, None, 0.).unwrap();
+            let pixel = result.as_rgb16().unwrap().get_pixel(3, 3).0;
+            pixel.iter().map(|&v| (i32::from(v) - 1000).abs()).sum()
+        };
+        assert!(error(DemosaicAlgorithm::Vng) < error(DemosaicAlgorithm::Bilinear));
+    }
+}
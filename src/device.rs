@@ -0,0 +1,306 @@
+use crate::actions::SUPPORTED_ACTIONS;
+use crate::backend::CameraDescriptor;
+use crate::bulb_calibration::{BulbCalibration, BulbCalibrations};
+use crate::camera::{CurrentExposure, MyCamera, State, StopExposure};
+use crate::capture::handle_pending_files;
+use crate::config::{CameraConfig, InFlightExposure, ShutdownConfig};
+use crate::device_ids;
+use crate::dimensions::KnownDimensions;
+use crate::error::convert_err;
+use crate::sensors::SensorInfo;
+use ascom_alpaca::api::Device;
+use ascom_alpaca::{ASCOMError, ASCOMErrorCode, ASCOMResult};
+use async_trait::async_trait;
+use atomic::Ordering;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::{RwLock, RwLockReadGuard};
+
+/// Physical camera assigned to a device slot.
+#[derive(Debug)]
+pub(crate) struct CameraIdentity {
+    pub unique_id: String,
+    pub model: String,
+    pub config: CameraConfig,
+    pub sensor: SensorInfo,
+}
+
+#[derive(Debug)]
+pub(crate) struct DeviceSlot {
+    /// Name and unique ID reported while no camera has been assigned to this slot yet.
+    pub placeholder_name: String,
+    pub placeholder_id: String,
+    /// Camera assigned to this slot. Once set, the slot stays reserved for that camera
+    /// so that it gets the same device number when re-attached.
+    pub identity: OnceLock<CameraIdentity>,
+    pub known_dimensions: Arc<KnownDimensions>,
+    pub bulb_calibrations: Arc<BulbCalibrations>,
+    /// Current port of the assigned camera, or `None` if it's not attached.
+    pub descriptor: parking_lot::RwLock<Option<CameraDescriptor>>,
+    pub camera: RwLock<Option<MyCamera>>,
+    /// Set once the driver is shutting down, so that no new exposures or connections are started.
+    pub shutting_down: AtomicBool,
+}
+
+/// Alpaca device backed by a slot that is associated with a physical camera at runtime.
+///
+/// Alpaca devices can't be registered after the server has started, so instead we register
+/// a fixed number of slots and let [`Discovery`](crate::discovery::Discovery) attach cameras to them as they come and go.
+/// This is a cheap handle so that the discovery task can keep its own copy of each device.
+#[derive(Debug, Clone)]
+pub(crate) struct MyCameraDevice(Arc<DeviceSlot>);
+
+impl std::ops::Deref for MyCameraDevice {
+    type Target = DeviceSlot;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl MyCameraDevice {
+    pub fn new_slot(
+        index: usize,
+        known_dimensions: Arc<KnownDimensions>,
+        bulb_calibrations: Arc<BulbCalibrations>,
+    ) -> Self {
+        Self(Arc::new(DeviceSlot {
+            placeholder_name: format!("Camera slot {}", index + 1),
+            placeholder_id: device_ids::placeholder_id(index),
+            identity: OnceLock::new(),
+            known_dimensions,
+            bulb_calibrations,
+            descriptor: Default::default(),
+            camera: Default::default(),
+            shutting_down: AtomicBool::new(false),
+        }))
+    }
+
+    /// Measured bulb timing of the assigned camera, if it has been calibrated.
+    pub fn bulb_calibration(&self) -> Option<BulbCalibration> {
+        self.bulb_calibrations.get(self.assigned_id()?)
+    }
+
+    pub fn assigned_id(&self) -> Option<&str> {
+        self.identity
+            .get()
+            .map(|identity| identity.unique_id.as_str())
+    }
+
+    /// Reserve this slot for the given camera.
+    ///
+    /// Panics if the slot is already assigned.
+    pub fn assign(&self, identity: CameraIdentity) {
+        self.identity
+            .set(identity)
+            .expect("device slot is already assigned");
+    }
+
+    pub fn descriptor(&self) -> Option<CameraDescriptor> {
+        self.descriptor.read().clone()
+    }
+
+    pub fn attach(&self, descriptor: CameraDescriptor) {
+        *self.descriptor.write() = Some(descriptor);
+    }
+
+    /// Mark the camera as physically detached.
+    ///
+    /// If a client is connected, we keep the connection around in an error state
+    /// so that it can be transparently restored once the camera is re-attached.
+    pub async fn detach(&self) {
+        *self.descriptor.write() = None;
+        if let Ok(camera) = self.camera().await {
+            camera.link_lost.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Record that an operation failed because we lost connection to the camera and try to restore it.
+    pub async fn connection_lost(&self) {
+        if let Ok(camera) = self.camera().await {
+            camera.link_lost.store(true, Ordering::Relaxed);
+        }
+        let _ = self.restore_connection().await;
+    }
+
+    /// Re-open the camera if we've previously lost connection to it.
+    ///
+    /// If the camera is currently detached, this will fail and has to be retried after
+    /// the discovery task re-attaches it.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn restore_connection(&self) -> eyre::Result<()> {
+        let mut camera = self.camera.write().await;
+
+        let Some(camera) = camera.as_mut() else {
+            // Client has disconnected in the meanwhile, nothing to restore.
+            return Ok(());
+        };
+
+        if !*camera.link_lost.get_mut() {
+            return Ok(());
+        }
+
+        let descriptor = self
+            .descriptor()
+            .ok_or_else(|| eyre::eyre!("Camera is not attached"))?;
+
+        camera.release();
+        camera.reopen(descriptor.open().await?).await?;
+
+        tracing::info!("Restored connection to the camera");
+
+        Ok(())
+    }
+
+    pub async fn camera(&self) -> ASCOMResult<RwLockReadGuard<'_, MyCamera>> {
+        RwLockReadGuard::try_map(self.camera.read().await, |camera| camera.as_ref())
+            .map_err(|_| ASCOMError::NOT_CONNECTED)
+    }
+
+    /// Wind the device down before the driver exits: deal with the exposure in progress
+    /// and any files it left on the camera according to `config`, then disconnect.
+    pub async fn shutdown(&self, config: &ShutdownConfig) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+
+        let done_rx = match self.camera().await {
+            Ok(camera) => match &*camera.state().await {
+                State::InExposure(CurrentExposure { done_rx, .. }) => Some(done_rx.clone()),
+                _ => None,
+            },
+            Err(_) => return,
+        };
+
+        let timeout = Duration::from_secs_f64(config.timeout);
+
+        if let Some(mut done_rx) = done_rx {
+            let finished = config.exposure == InFlightExposure::Finish && {
+                tracing::info!("Waiting for the current exposure to finish");
+                tokio::time::timeout(timeout, done_rx.wait_for(|&done| done))
+                    .await
+                    .is_ok()
+            };
+            if !finished {
+                tracing::info!("Aborting the current exposure");
+                if tokio::time::timeout(timeout, self.stop(false))
+                    .await
+                    .is_err()
+                {
+                    tracing::warn!("Exposure didn't stop in time");
+                }
+            }
+        }
+
+        if let Some(camera) = self
+            .camera()
+            .await
+            .ok()
+            .and_then(|camera| camera.backend.clone())
+        {
+            if let Err(err) = handle_pending_files(&*camera, config.pending_files).await {
+                tracing::warn!("Couldn't handle files left on the camera: {err:#}");
+            }
+        }
+
+        if let Err(err) = self.set_connected(false).await {
+            tracing::warn!("Couldn't disconnect: {err:#}");
+        }
+    }
+
+    pub async fn stop(&self, want_image: bool) -> ASCOMResult {
+        // Make sure locks are not held when waiting for `done`.
+        let mut done_rx = match &mut *self.camera().await?.state().await {
+            State::InExposure(CurrentExposure {
+                stop_tx, done_rx, ..
+            }) => {
+                if let Some(stop_tx) = stop_tx.take() {
+                    let _ = stop_tx.send(StopExposure { want_image });
+                }
+                done_rx.clone()
+            }
+            _ => return Ok(()),
+        };
+        // if channel is already closed, this will return an error - ignore it as it still means that we're done
+        let _ = done_rx.wait_for(|&done| done).await;
+        Ok(())
+    }
+}
+
+#[allow(unused_variables)]
+#[async_trait]
+impl Device for MyCameraDevice {
+    fn unique_id(&self) -> &str {
+        self.assigned_id().unwrap_or(&self.placeholder_id)
+    }
+
+    async fn connected(&self) -> ASCOMResult<bool> {
+        Ok(self.camera().await.is_ok())
+    }
+
+    async fn set_connected(&self, connected: bool) -> ASCOMResult {
+        let mut camera = self.camera.write().await;
+
+        if connected == camera.is_some() {
+            return Ok(());
+        }
+
+        if connected && self.shutting_down.load(Ordering::Relaxed) {
+            return Err(ASCOMError::invalid_operation("Driver is shutting down"));
+        }
+
+        *camera = if connected {
+            let (Some(identity), Some(descriptor)) = (self.identity.get(), self.descriptor())
+            else {
+                return Err(ASCOMError::new(
+                    ASCOMErrorCode::NOT_CONNECTED,
+                    "Camera is not attached",
+                ));
+            };
+
+            Some(
+                MyCamera::new(
+                    descriptor.open().await.map_err(convert_err)?,
+                    identity,
+                    &self.known_dimensions,
+                )
+                .await
+                .map_err(convert_err)?,
+            )
+        } else {
+            None
+        };
+
+        Ok(())
+    }
+
+    async fn description(&self) -> ASCOMResult<String> {
+        // TODO: is there better description text? We already use model in the name.
+        Ok(match self.identity.get() {
+            Some(identity) => identity.model.clone(),
+            None => "Reserved for a camera attached later".to_owned(),
+        })
+    }
+
+    async fn driver_info(&self) -> ASCOMResult<String> {
+        Ok(env!("CARGO_PKG_DESCRIPTION").to_owned())
+    }
+
+    async fn driver_version(&self) -> ASCOMResult<String> {
+        Ok(env!("CARGO_PKG_VERSION").to_owned())
+    }
+
+    async fn action(&self, action: String, parameters: String) -> ASCOMResult<String> {
+        self.run_action(&action, &parameters).await
+    }
+
+    async fn supported_actions(&self) -> ASCOMResult<Vec<String>> {
+        Ok(SUPPORTED_ACTIONS.map(str::to_owned).to_vec())
+    }
+
+    fn static_name(&self) -> &str {
+        match self.identity.get() {
+            Some(identity) => &identity.model,
+            None => &self.placeholder_name,
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use crate::backend::CameraDescriptor;
+use crate::state_file;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Namespace for name-based (v5) UUIDs generated by this driver.
+const NAMESPACE: Uuid = Uuid::from_u128(0x0c6f_0f8e_5d7a_4b8e_9a55_3c1e_2b7d_a4f1);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct KnownCamera {
+    unique_id: String,
+    model: String,
+    serial_number: Option<String>,
+    port: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KnownCameras {
+    cameras: Vec<KnownCamera>,
+}
+
+/// Persistent registry of unique IDs assigned to cameras we've seen so far.
+#[derive(Debug)]
+pub(crate) struct DeviceIds {
+    path: PathBuf,
+    known: KnownCameras,
+    /// IDs already handed out in this session, to avoid giving the same one to two cameras.
+    claimed: HashSet<String>,
+}
+
+impl DeviceIds {
+    pub fn load(state_dir: &std::path::Path) -> eyre::Result<Self> {
+        let path = state_dir.join("cameras.toml");
+        Ok(Self {
+            known: state_file::load(&path)?,
+            path,
+            claimed: HashSet::new(),
+        })
+    }
+
+    /// Get a stable unique ID for the camera.
+    ///
+    /// If the serial number is known, the ID is derived from it and the model, so it stays the same
+    /// regardless of the port. Otherwise we try to find a previously seen camera of the same model
+    /// (preferring the same port), and only derive a new ID from the model and port as a last resort.
+    #[tracing::instrument(skip(self), ret, err)]
+    pub fn assign(
+        &mut self,
+        descriptor: &CameraDescriptor,
+        serial_number: Option<&str>,
+    ) -> eyre::Result<String> {
+        let known_idx = match serial_number {
+            Some(serial_number) => self.known.cameras.iter().position(|camera| {
+                camera.model == descriptor.model()
+                    && camera.serial_number.as_deref() == Some(serial_number)
+            }),
+            None => {
+                let candidates = || {
+                    self.known.cameras.iter().enumerate().filter(|(_, camera)| {
+                        camera.model == descriptor.model()
+                            && camera.serial_number.is_none()
+                            && !self.claimed.contains(&camera.unique_id)
+                    })
+                };
+                candidates()
+                    .find(|(_, camera)| camera.port == descriptor.port())
+                    .or_else(|| candidates().next())
+                    .map(|(idx, _)| idx)
+            }
+        };
+
+        let unique_id = match known_idx {
+            Some(idx) => {
+                let camera = &mut self.known.cameras[idx];
+                camera.port = descriptor.port();
+                camera.unique_id.clone()
+            }
+            None => {
+                let name = match serial_number {
+                    Some(serial_number) => format!("{}\0{serial_number}", descriptor.model()),
+                    None => format!("{}\0{}", descriptor.model(), descriptor.port()),
+                };
+                // In the unlikely case another camera has already taken the port-derived ID
+                // (e.g. it has since moved to a different port), keep hashing until we find a free one.
+                let unique_id = std::iter::successors(Some(name), |name| Some(format!("{name}\0")))
+                    .map(|name| Uuid::new_v5(&NAMESPACE, name.as_bytes()).to_string())
+                    .find(|id| {
+                        !self
+                            .known
+                            .cameras
+                            .iter()
+                            .any(|camera| camera.unique_id == *id)
+                    })
+                    .expect("infinite iterator");
+                self.known.cameras.push(KnownCamera {
+                    unique_id: unique_id.clone(),
+                    model: descriptor.model().to_owned(),
+                    serial_number: serial_number.map(str::to_owned),
+                    port: descriptor.port(),
+                });
+                unique_id
+            }
+        };
+
+        eyre::ensure!(
+            self.claimed.insert(unique_id.clone()),
+            "Camera {} with serial number {serial_number:?} is already registered",
+            descriptor.model()
+        );
+
+        state_file::save(&self.path, &self.known)?;
+
+        Ok(unique_id)
+    }
+
+    /// Mark the ID as no longer in use, e.g. because the camera was detached.
+    pub fn release(&mut self, unique_id: &str) {
+        self.claimed.remove(unique_id);
+    }
+}
+
+/// Unique ID reported by a device slot that hasn't been assigned to any camera yet.
+pub(crate) fn placeholder_id(slot_index: usize) -> String {
+    Uuid::new_v5(&NAMESPACE, format!("slot\0{slot_index}").as_bytes()).to_string()
+}
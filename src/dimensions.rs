@@ -0,0 +1,37 @@
+use crate::camera::Size;
+use crate::state_file;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Sensor dimensions observed during previous sessions, persisted in the state directory.
+///
+/// These take precedence over the sensor database, as they come from actual images.
+#[derive(Debug)]
+pub(crate) struct KnownDimensions {
+    path: PathBuf,
+    observed: parking_lot::Mutex<BTreeMap<String, Size>>,
+}
+
+impl KnownDimensions {
+    pub fn load(state_dir: &std::path::Path) -> eyre::Result<Self> {
+        let path = state_dir.join("dimensions.toml");
+        Ok(Self {
+            observed: parking_lot::Mutex::new(state_file::load(&path)?),
+            path,
+        })
+    }
+
+    pub fn get(&self, model: &str) -> Option<Size> {
+        self.observed.lock().get(model).copied()
+    }
+
+    /// Remember dimensions of an actual image from the camera.
+    pub fn observe(&self, model: &str, size: Size) -> eyre::Result<()> {
+        let mut observed = self.observed.lock();
+        if observed.get(model) == Some(&size) {
+            return Ok(());
+        }
+        observed.insert(model.to_owned(), size);
+        state_file::save(&self.path, &*observed)
+    }
+}
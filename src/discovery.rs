@@ -0,0 +1,148 @@
+use crate::backend::{self, CameraDescriptor};
+use crate::config::{CamerasConfig, SimulatorConfig};
+use crate::device::{CameraIdentity, MyCameraDevice};
+use crate::device_ids::DeviceIds;
+use crate::sensors::{self, SensorInfo};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Keeps registered device slots in sync with the cameras attached to the host.
+#[derive(Debug)]
+pub(crate) struct Discovery {
+    config: CamerasConfig,
+    simulator: Arc<SimulatorConfig>,
+    sensors: BTreeMap<String, SensorInfo>,
+    device_ids: DeviceIds,
+    devices: Vec<MyCameraDevice>,
+}
+
+impl Discovery {
+    pub fn new(
+        config: CamerasConfig,
+        simulator: Arc<SimulatorConfig>,
+        sensors: BTreeMap<String, SensorInfo>,
+        device_ids: DeviceIds,
+        devices: Vec<MyCameraDevice>,
+    ) -> Self {
+        Self {
+            config,
+            simulator,
+            sensors,
+            device_ids,
+            devices,
+        }
+    }
+
+    /// List attached cameras that match the configured filters.
+    pub async fn list_cameras(
+        config: &CamerasConfig,
+        simulator: &Arc<SimulatorConfig>,
+    ) -> eyre::Result<Vec<CameraDescriptor>> {
+        Ok(backend::list_cameras(simulator)
+            .await?
+            .into_iter()
+            .filter(|descriptor| {
+                let should_register = config.should_register(descriptor.model());
+                if !should_register {
+                    tracing::trace!(?descriptor, "Skipping camera excluded by configuration");
+                }
+                should_register
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    pub async fn rescan(&mut self) -> eyre::Result<()> {
+        let attached = Self::list_cameras(&self.config, &self.simulator).await?;
+
+        for device in &self.devices {
+            if let Some(descriptor) = device.descriptor() {
+                if !attached.contains(&descriptor) {
+                    tracing::info!(?descriptor, "Camera was detached");
+                    device.detach().await;
+                    if let Some(unique_id) = device.assigned_id() {
+                        self.device_ids.release(unique_id);
+                    }
+                }
+            }
+        }
+
+        for descriptor in attached {
+            if self
+                .devices
+                .iter()
+                .any(|device| device.descriptor().as_ref() == Some(&descriptor))
+            {
+                continue;
+            }
+            // Don't bail out on a single camera - it might be still initialising,
+            // in which case we'll just retry on the next rescan.
+            if let Err(err) = self.attach(&descriptor).await {
+                tracing::warn!(?descriptor, "Couldn't register camera: {err:#}");
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn attach(&mut self, descriptor: &CameraDescriptor) -> eyre::Result<()> {
+        // Briefly open the camera to read its serial number, which is used to derive a stable unique ID.
+        let serial_number = descriptor.open().await?.serial_number().await;
+
+        let unique_id = self
+            .device_ids
+            .assign(descriptor, serial_number.as_deref())?;
+
+        let device = match self
+            .devices
+            .iter()
+            .find(|device| device.assigned_id() == Some(unique_id.as_str()))
+        {
+            Some(device) => {
+                tracing::info!(?descriptor, unique_id, "Camera was re-attached");
+                device
+            }
+            None => {
+                let Some(device) = self
+                    .devices
+                    .iter()
+                    .find(|device| device.assigned_id().is_none())
+                else {
+                    self.device_ids.release(&unique_id);
+                    eyre::bail!(
+                        "No free device slots left; increase `cameras.spare_slots` in the config"
+                    );
+                };
+                tracing::info!(?descriptor, unique_id, "Registered new camera");
+                device.assign(CameraIdentity {
+                    config: self.config.for_model(descriptor.model()),
+                    sensor: sensors::lookup(&self.sensors, descriptor.model()),
+                    model: descriptor.model().to_owned(),
+                    unique_id,
+                });
+                device
+            }
+        };
+
+        device.attach(descriptor.clone());
+
+        // If a client stayed connected while the camera was detached, pick up where we left off.
+        // Errors are already logged by `restore_connection` itself.
+        let _ = device.restore_connection().await;
+
+        Ok(())
+    }
+
+    /// Periodically rescan attached cameras.
+    pub async fn run(mut self, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            // Errors are already logged by `rescan` itself, and we want to keep trying.
+            let _ = self.rescan().await;
+        }
+    }
+}
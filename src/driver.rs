@@ -0,0 +1,121 @@
+use crate::bulb_calibration::BulbCalibrations;
+use crate::config::{Config, ShutdownConfig};
+use crate::device::MyCameraDevice;
+use crate::device_ids::DeviceIds;
+use crate::dimensions::KnownDimensions;
+use crate::discovery::Discovery;
+use ascom_alpaca::api::CargoServerInfo;
+use ascom_alpaca::{BoundServer, Server};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+
+/// The driver with its devices registered and the Alpaca server bound, ready to serve requests.
+pub struct Driver {
+    server: BoundServer,
+    devices: Vec<MyCameraDevice>,
+    shutdown: ShutdownConfig,
+}
+
+impl Driver {
+    /// Register device slots for attached cameras, start discovery and bind the server.
+    pub async fn new(config: Config) -> eyre::Result<Self> {
+        let mut server = Server {
+            info: CargoServerInfo!(),
+            listen_addr: (config.server.listen_addr, config.server.port).into(),
+            ..Default::default()
+        };
+
+        let state_dir = config.state_dir()?;
+
+        let simulator = Arc::new(config.simulator);
+        let mut sensors = config.sensors;
+        if simulator.cameras > 0 {
+            // Let simulated cameras report their dimensions right away.
+            let sensor = sensors.entry(simulator.model.clone()).or_default();
+            sensor.width = sensor.width.or(Some(simulator.width));
+            sensor.height = sensor.height.or(Some(simulator.height));
+        }
+
+        let attached_count = Discovery::list_cameras(&config.cameras, &simulator)
+            .await?
+            .len();
+
+        let known_dimensions = Arc::new(KnownDimensions::load(&state_dir)?);
+        let bulb_calibrations = Arc::new(BulbCalibrations::load(&state_dir)?);
+
+        let devices = (0..attached_count + config.cameras.spare_slots)
+            .map(|index| {
+                MyCameraDevice::new_slot(
+                    index,
+                    Arc::clone(&known_dimensions),
+                    Arc::clone(&bulb_calibrations),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        for device in &devices {
+            server.devices.register(device.clone());
+        }
+
+        let rescan_interval = config.cameras.rescan_interval;
+
+        let mut discovery = Discovery::new(
+            config.cameras,
+            simulator,
+            sensors,
+            DeviceIds::load(&state_dir)?,
+            devices.clone(),
+        );
+
+        discovery.rescan().await?;
+
+        if rescan_interval > 0. {
+            tokio::spawn(discovery.run(Duration::from_secs_f64(rescan_interval)));
+        }
+
+        tracing::debug!(?server.devices, "Registered Alpaca devices");
+
+        Ok(Self {
+            server: server.bind().await?,
+            devices,
+            shutdown: config.shutdown,
+        })
+    }
+
+    /// Address the Alpaca server is listening on, e.g. to find out an auto-assigned port.
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.server.listen_addr()
+    }
+
+    /// Serve requests until `shutdown_signal` resolves, then wind down every camera.
+    pub async fn run(
+        self,
+        shutdown_signal: impl std::future::Future<Output = std::io::Result<()>>,
+    ) -> eyre::Result<()> {
+        let Self {
+            server,
+            devices,
+            shutdown,
+        } = self;
+
+        select! {
+            result = server.start() => result.map(|never| match never {}),
+            // Keep serving requests meanwhile, so that clients learn about the shutdown.
+            result = async {
+                shutdown_signal.await?;
+                tracing::info!("Shutting down");
+                futures_util::future::join_all(
+                    devices.iter().map(|device| device.shutdown(&shutdown)),
+                )
+                .await;
+                eyre::Ok(())
+            } => {
+                // Returning shuts down the runtime, dropping any exposures that didn't stop in time,
+                // which in turn releases any held bulb.
+                result
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use ascom_alpaca::{ASCOMError, ASCOMErrorCode};
+use gphoto2::error::ErrorKind;
+use std::fmt;
+
+/// Failures reported to clients with driver-specific ASCOM codes (0x500 and up).
+///
+/// Codes follow the variant order and are documented in the README, so only append new ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DriverError {
+    /// The camera is still busy, e.g. writing the previous frame to the card.
+    CameraBusy,
+    /// No space left on the camera's card or the local disk.
+    StorageFull,
+    /// The camera produced a file format we can't decode.
+    UnsupportedFile,
+    /// A downloaded file is truncated or malformed.
+    CorruptedData,
+    /// The camera reported a failure of its own.
+    CameraFailure,
+    /// The camera didn't respond in time.
+    Timeout,
+}
+
+impl DriverError {
+    pub const fn code(self) -> ASCOMErrorCode {
+        ASCOMErrorCode::new_for_driver(self as u16)
+    }
+}
+
+impl fmt::Display for DriverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::CameraBusy => "Camera is busy",
+            Self::StorageFull => "Storage is full",
+            Self::UnsupportedFile => "Unsupported file format",
+            Self::CorruptedData => "Corrupted file",
+            Self::CameraFailure => "Camera reported a failure",
+            Self::Timeout => "Camera timed out",
+        })
+    }
+}
+
+impl std::error::Error for DriverError {}
+
+/// Whether the error means that we've lost connection to the camera, e.g. due to a USB hiccup.
+fn is_connection_lost(err: &eyre::Report) -> bool {
+    err.chain()
+        .filter_map(|err| err.downcast_ref::<gphoto2::Error>())
+        .any(|err| {
+            matches!(
+                err.kind(),
+                // Not the generic `Io`, which gphoto2 also uses for any `std::io::Error`.
+                ErrorKind::IoRead
+                    | ErrorKind::IoWrite
+                    | ErrorKind::IoUpdate
+                    | ErrorKind::IoUsbClaim
+                    | ErrorKind::IoUsbClearHalt
+                    | ErrorKind::IoUsbFind
+                    | ErrorKind::IoLock
+                    | ErrorKind::UnknownPort
+            )
+        })
+}
+
+fn gphoto2_code(kind: ErrorKind) -> Option<ASCOMErrorCode> {
+    Some(match kind {
+        ErrorKind::BadParameters | ErrorKind::FixedLimitExceeded => ASCOMErrorCode::INVALID_VALUE,
+        ErrorKind::NotSupported => ASCOMErrorCode::NOT_IMPLEMENTED,
+        ErrorKind::CameraBusy => DriverError::CameraBusy.code(),
+        ErrorKind::NoSpace => DriverError::StorageFull.code(),
+        ErrorKind::CorruptedData => DriverError::CorruptedData.code(),
+        ErrorKind::CameraError => DriverError::CameraFailure.code(),
+        ErrorKind::Timeout => DriverError::Timeout.code(),
+        _ => return None,
+    })
+}
+
+/// Code for the outermost cause in the chain that we know how to classify.
+fn error_code(err: &eyre::Report) -> Option<ASCOMErrorCode> {
+    err.chain().find_map(|cause| {
+        if let Some(err) = cause.downcast_ref::<DriverError>() {
+            Some(err.code())
+        } else if let Some(err) = cause.downcast_ref::<gphoto2::Error>() {
+            gphoto2_code(err.kind())
+        } else if let Some(err) = cause.downcast_ref::<rawler::RawlerError>() {
+            Some(match err {
+                rawler::RawlerError::Unsupported { .. } => DriverError::UnsupportedFile.code(),
+                _ => DriverError::CorruptedData.code(),
+            })
+        } else if let Some(err) = cause.downcast_ref::<image::ImageError>() {
+            match err {
+                image::ImageError::Unsupported(_) => Some(DriverError::UnsupportedFile.code()),
+                image::ImageError::Decoding(_) => Some(DriverError::CorruptedData.code()),
+                image::ImageError::Limits(_) => Some(ASCOMErrorCode::INVALID_VALUE),
+                _ => None,
+            }
+        } else if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+            (err.kind() == std::io::ErrorKind::StorageFull)
+                .then_some(DriverError::StorageFull.code())
+        } else {
+            None
+        }
+    })
+}
+
+/// Map an internal error to the most specific ASCOM error, keeping the whole chain in the message.
+pub(crate) fn convert_err(err: impl Into<eyre::Report>) -> ASCOMError {
+    let err = err.into();
+    let error = if is_connection_lost(&err) {
+        ASCOMError::new(
+            ASCOMErrorCode::NOT_CONNECTED,
+            format_args!("Lost connection to the camera: {err:#}"),
+        )
+    } else {
+        ASCOMError::new(
+            error_code(&err).unwrap_or(ASCOMErrorCode::UNSPECIFIED),
+            format_args!("Camera error: {err:#}"),
+        )
+    };
+    tracing::warn!(code = %error.code, "{err:?}");
+    error
+}
@@ -0,0 +1,44 @@
+use crate::fits::{self, ExposureMetadata};
+use crate::xisf;
+use ascom_alpaca::api::ImageArray;
+use serde::Deserialize;
+
+/// File format for saved images.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SaveFormat {
+    #[default]
+    Fits,
+    Xisf,
+}
+
+impl SaveFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "fits" => Some(Self::Fits),
+            "xisf" => Some(Self::Xisf),
+            _ => None,
+        }
+    }
+
+    /// Original name for the archive template, which only takes the extension from it.
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Self::Fits => "image.fits",
+            Self::Xisf => "image.xisf",
+        }
+    }
+
+    /// Encode the image; `compress` only applies to formats that support it.
+    pub fn encode(
+        self,
+        image: &ImageArray,
+        metadata: &ExposureMetadata,
+        compress: bool,
+    ) -> Vec<u8> {
+        match self {
+            Self::Fits => fits::encode(image, metadata),
+            Self::Xisf => xisf::encode(image, metadata, compress),
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use crate::archive::FrameInfo;
+use crate::binning::BinningMode;
+use crate::camera::{
+    files_per_capture, CurrentExposure, Size, State, StopExposure, SuccessfulExposure, Temperature,
+};
+use crate::capture::{bulb_capture, download_capture, wait_for_files};
+use crate::convert_image::convert_dynamic_image;
+use crate::device::MyCameraDevice;
+use crate::error::convert_err;
+use crate::fits::ExposureMetadata;
+use crate::parse_image::{bayer_offset, ImgWithMetadata};
+use crate::shutter_speed::ExposureMechanism;
+use crate::{binning, demosaic};
+use ascom_alpaca::api::CameraState;
+use ascom_alpaca::{ASCOMError, ASCOMErrorCode, ASCOMResult};
+use atomic::{Atomic, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{oneshot, watch};
+
+macro_rules! crop_rect_side {
+    ($area:ident, $subframe:ident, $start:ident, $len:ident) => {
+        match $subframe.$start.checked_add($subframe.$len) {
+            Some(subframe_end) if subframe_end <= $area.$len => { /* in bounds */ }
+            _ => {
+                return Err(ASCOMError::invalid_value(format_args!(
+                    "Subframe {}+{} is out of image bounds",
+                    stringify!($start),
+                    stringify!($len)
+                )))
+            }
+        }
+        $area.$start += $subframe.$start;
+        $area.$len = $subframe.$len;
+    };
+}
+
+impl MyCameraDevice {
+    /// Start an exposure of `duration` seconds in a background task.
+    pub async fn expose(&self, duration: f64, light: bool) -> ASCOMResult {
+        if duration < 0. {
+            return Err(ASCOMError::invalid_value("Duration must be non-negative"));
+        }
+        let duration = Duration::try_from_secs_f64(duration).map_err(ASCOMError::invalid_value)?;
+        // Zero-length requests still get the shortest shutter speed, so remember them before that.
+        let bias = !light && duration.is_zero();
+        if self.shutting_down.load(Ordering::Relaxed) {
+            return Err(ASCOMError::invalid_operation("Driver is shutting down"));
+        }
+        // If a previous operation has lost connection to the camera, try to restore it first.
+        self.restore_connection().await.map_err(|err| {
+            ASCOMError::new(
+                ASCOMErrorCode::NOT_CONNECTED,
+                format_args!("Couldn't restore connection to the camera: {err:#}"),
+            )
+        })?;
+        let camera = self.camera().await?;
+        // Reject a bad subframe now rather than after the whole exposure.
+        camera.validate_subframe()?;
+        let state = Arc::clone(&camera.state);
+        let mut state_lock = camera.state().await;
+        if matches!(*state_lock, State::InExposure(_)) {
+            return Err(ASCOMError::invalid_operation("Camera is already exposing"));
+        }
+        let last_exposure_duration = Arc::clone(&camera.last_exposure_duration);
+        let last_exposure_start_time = Arc::clone(&camera.last_exposure_start_time);
+        let bulb_toggle = camera.bulb.clone().ok_or(ASCOMError::NOT_CONNECTED)?;
+        let bin = camera.bin();
+        let binning_mode = camera.binning_mode;
+        let bayer_binning = camera.bayer_binning;
+        let debayer = camera.debayer.load(Ordering::Relaxed);
+        let demosaic_algorithm = camera.demosaic;
+        let white_balance = camera.white_balance;
+        let raw_levels = camera.raw_levels;
+        // Subframe is in binned pixels, but we crop the unbinned image.
+        let subframe = camera.subframe.read().map(|subframe| image::math::Rect {
+            x: subframe.x * bin,
+            y: subframe.y * bin,
+            width: subframe.width * bin,
+            height: subframe.height * bin,
+        });
+        let dimensions = Arc::clone(&camera.dimensions);
+        let sensor_cfa = Arc::clone(&camera.cfa);
+        let max_adu = Arc::clone(&camera.max_adu);
+        let max_signal = Arc::clone(&camera.max_signal);
+        let gain_scale = Arc::clone(&camera.gain_scale);
+        let last_temperature = Arc::clone(&camera.last_temperature);
+        let last_preview = Arc::clone(&camera.last_preview);
+        let expected_files = files_per_capture(&camera.image_format.choice());
+        let keep_on_card = camera.keep_on_card;
+        let archive = camera.archive.clone();
+        let iso = camera.iso.choice();
+        let sensor_gain = camera.sensor.at_iso(&iso).gain;
+        let target = camera.target.lock().clone();
+        let save_archive = camera.save_archive.clone().filter(|_| camera.auto_save);
+        let save_format = camera.save_format;
+        let xisf_compression = camera.xisf_compression;
+        let temperature_max_age = camera.temperature_max_age;
+        let bulb_calibration = self.bulb_calibration();
+        let mechanism = match &camera.shutter_speeds {
+            Some(shutter_speeds) => shutter_speeds.mechanism(duration, camera.min_bulb_duration),
+            None => ExposureMechanism::Bulb,
+        };
+        let duration = match &mechanism {
+            ExposureMechanism::Timed { duration, .. } => *duration,
+            ExposureMechanism::Bulb => duration,
+        };
+        tracing::debug!(?mechanism, ?duration, "Starting exposure");
+
+        // Do this before the shot - otherwise we risk trying to update camera config
+        // in the middle of a bulb exposure, which will result in a "camera busy" error.
+        let backend = camera.backend()?;
+        if let Err(err) = async {
+            camera.iso.apply(&**backend).await?;
+            camera.image_format.apply(&**backend).await?;
+            if let Some(shutter_speeds) = &camera.shutter_speeds {
+                shutter_speeds.select(&mechanism)?.apply(&**backend).await?;
+            }
+            Ok(())
+        }
+        .await
+        .map_err(convert_err)
+        {
+            if err.code == ASCOMErrorCode::NOT_CONNECTED {
+                camera.link_lost.store(true, Ordering::Relaxed);
+            }
+            return Err(err);
+        }
+
+        let camera = Arc::clone(backend);
+        let device = self.clone();
+        let (stop_tx, stop_rx) = oneshot::channel::<StopExposure>();
+        let (done_tx, done_rx) = watch::channel(false);
+        let exposing_state = Arc::new(Atomic::new(CameraState::Waiting));
+
+        *state_lock = State::InExposure(CurrentExposure {
+            // this might slightly differ from actual start in the async task;
+            // we use this only for progress reporting
+            rough_start: Instant::now(),
+            state: Arc::clone(&exposing_state),
+            stop_tx: Some(stop_tx),
+            done_rx,
+            expected_duration: duration,
+        });
+
+        tokio::task::spawn(async move {
+            let result = async {
+                let start_utc = SystemTime::now();
+                last_exposure_start_time.store(Some(start_utc), Ordering::Relaxed);
+                let (files, duration, calibrated) = match mechanism {
+                    ExposureMechanism::Timed { duration, .. } => {
+                        exposing_state.store(CameraState::Exposing, Ordering::Relaxed);
+                        // The camera times the exposure itself and reports the file once it's written.
+                        let mut files = vec![camera.capture().await.map_err(convert_err)?];
+                        // Timed captures can't be interrupted, so honour an abort only once it's done.
+                        let mut stop_rx = stop_rx;
+                        if matches!(stop_rx.try_recv(), Ok(StopExposure { want_image: false })) {
+                            return Err(ASCOMError::invalid_operation("Exposure was aborted"));
+                        }
+
+                        // Any other files of the capture are only reported as events.
+                        wait_for_files(&*camera, &mut files, expected_files).await?;
+                        (files, duration, false)
+                    }
+                    ExposureMechanism::Bulb => {
+                        let hold = bulb_calibration.map_or(duration, |c| c.hold_time(duration));
+                        let stop = async move {
+                            match stop_rx.await {
+                                Ok(stop) => stop.want_image,
+                                Err(_) => std::future::pending().await,
+                            }
+                        };
+                        let (files, held) = bulb_capture(
+                            &*camera,
+                            bulb_toggle,
+                            hold,
+                            expected_files,
+                            stop,
+                            &exposing_state,
+                        )
+                        .await?;
+                        let duration = match bulb_calibration {
+                            Some(calibration) => {
+                                Duration::from_secs_f64(calibration.exposure_time(held))
+                            }
+                            None => held,
+                        };
+                        (files, duration, bulb_calibration.is_some())
+                    }
+                };
+
+                exposing_state.store(CameraState::Download, Ordering::Relaxed);
+                let mut frame = FrameInfo {
+                    start: start_utc,
+                    exposure: duration.as_secs_f64(),
+                    iso,
+                    light,
+                    bias,
+                    target,
+                };
+                let (data, preview) = download_capture(
+                    &*camera,
+                    files,
+                    keep_on_card,
+                    archive.as_deref().map(|archive| (archive, &frame)),
+                )
+                .await
+                .map_err(convert_err)?;
+                *last_preview.lock() = preview;
+                let mut img = ImgWithMetadata::from_data(data).map_err(convert_err)?;
+                let levels_scale = img.apply_levels(raw_levels);
+
+                let mut temperature = img.temperature;
+                if temperature.is_none() && camera.has_temperature() {
+                    // Some bodies don't record temperature in their files (e.g. Nikon encrypts its
+                    // maker notes), but the exposure is over, so it's safe to ask the camera.
+                    match camera.temperature().await {
+                        Ok(celsius) => temperature = Some(celsius),
+                        Err(err) => tracing::debug!("Couldn't read temperature: {err:#}"),
+                    }
+                }
+                if let Some(celsius) = temperature {
+                    *last_temperature.lock() = Some(Temperature {
+                        celsius,
+                        measured_at: Instant::now(),
+                    });
+                }
+
+                // Many bodies round bulb times they record, so a calibrated duration is more accurate.
+                if !calibrated {
+                    frame.exposure = img.exposure_time.unwrap_or(frame.exposure);
+                }
+                last_exposure_duration.store(Some(frame.exposure), Ordering::Relaxed);
+
+                let mut crop_area = img.crop_area;
+
+                let observed_dimensions = Size {
+                    width: crop_area.width,
+                    height: crop_area.height,
+                };
+                if *dimensions.read() != Some(observed_dimensions) {
+                    tracing::info!(
+                        ?observed_dimensions,
+                        "Updating sensor dimensions from the captured image"
+                    );
+                    *dimensions.write() = Some(observed_dimensions);
+                    if let Some(identity) = device.identity.get() {
+                        if let Err(err) = device
+                            .known_dimensions
+                            .observe(&identity.model, observed_dimensions)
+                        {
+                            tracing::warn!("Couldn't save sensor dimensions: {err:#}");
+                        }
+                    }
+                }
+
+                if let Some(cfa) = &img.cfa {
+                    *sensor_cfa.write() =
+                        Some(cfa.shift(crop_area.x as usize, crop_area.y as usize));
+                }
+
+                if let Some(subframe) = subframe {
+                    crop_rect_side!(crop_area, subframe, x, width);
+                    crop_rect_side!(crop_area, subframe, y, height);
+                }
+
+                let mut image =
+                    img.image
+                        .crop_imm(crop_area.x, crop_area.y, crop_area.width, crop_area.height);
+                let mut cfa = img
+                    .cfa
+                    .map(|cfa| cfa.shift(crop_area.x as usize, crop_area.y as usize));
+
+                if let Some(pattern) = cfa.as_ref().filter(|_| debayer) {
+                    // Demosaicing takes a while on large frames, don't block other tasks meanwhile.
+                    image = tokio::task::block_in_place(|| {
+                        demosaic::demosaic(
+                            &image,
+                            pattern,
+                            demosaic_algorithm,
+                            img.wb_coeffs.filter(|_| white_balance),
+                            img.levels.map_or(0., |levels| levels.mean_black()),
+                        )
+                    })
+                    .map_err(convert_err)?;
+                    cfa = None;
+                }
+
+                if bin > 1 {
+                    image = binning::bin_image(
+                        &image,
+                        bin,
+                        binning_mode,
+                        cfa.as_ref().map(|cfa| (cfa, bayer_binning)),
+                    )
+                    .map_err(convert_err)?;
+                }
+
+                let image = convert_dynamic_image(image).map_err(convert_err)?;
+
+                let image_gain_scale = match binning_mode {
+                    // Averaging spreads electrons from several pixels over the same ADU range.
+                    BinningMode::Average => f64::from(bin * bin) / levels_scale,
+                    BinningMode::Sum => 1. / levels_scale,
+                };
+                gain_scale.store(image_gain_scale, Ordering::Relaxed);
+                let scale = match binning_mode {
+                    BinningMode::Sum => bin * bin,
+                    BinningMode::Average => 1,
+                };
+                let binned_max_adu = (img.max_adu * scale).min(u16::MAX.into());
+                // Channels with the highest black level saturate first.
+                let black = img.levels.map_or(0, |levels| {
+                    u32::from(levels.black.into_iter().max().unwrap_or(0)) * scale
+                });
+                max_adu.store(binned_max_adu, Ordering::Relaxed);
+                max_signal.store(binned_max_adu.saturating_sub(black), Ordering::Relaxed);
+
+                let metadata = ExposureMetadata {
+                    frame,
+                    instrument: device.identity.get().map(|identity| identity.model.clone()),
+                    electrons_per_adu: sensor_gain.map(|gain| gain * image_gain_scale),
+                    // Binned frames are combined into superpixels, so there's no Bayer pattern left.
+                    bayer_offset: cfa.as_ref().filter(|_| bin == 1).and_then(bayer_offset),
+                    bin,
+                    origin: subframe
+                        .map_or((0, 0), |subframe| (subframe.x / bin, subframe.y / bin)),
+                    temperature: temperature.or_else(|| {
+                        let temperature = (*last_temperature.lock())?;
+                        (temperature.measured_at.elapsed() <= temperature_max_age)
+                            .then_some(temperature.celsius)
+                    }),
+                };
+
+                if let Some(save_archive) = &save_archive {
+                    if let Err(err) = tokio::task::block_in_place(|| {
+                        save_archive.save(
+                            &metadata.frame,
+                            save_format.file_name(),
+                            &save_format.encode(&image, &metadata, xisf_compression),
+                        )
+                    }) {
+                        tracing::warn!("Couldn't save image: {err:#}");
+                    }
+                }
+
+                Ok(SuccessfulExposure { image, metadata })
+            }
+            .await;
+
+            let connection_lost =
+                matches!(&result, Err(err) if err.code == ASCOMErrorCode::NOT_CONNECTED);
+
+            *state.lock().await = State::AfterExposure(result);
+
+            let _ = done_tx.send(true);
+
+            if connection_lost {
+                // Let go of the camera first, so that it can be opened again.
+                drop((camera, bulb_toggle));
+                device.connection_lost().await;
+            }
+        });
+
+        Ok(())
+    }
+}
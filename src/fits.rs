@@ -0,0 +1,234 @@
+use crate::archive::FrameInfo;
+use ascom_alpaca::api::ImageArray;
+
+const BLOCK_SIZE: usize = 2880;
+const CARD_SIZE: usize = 80;
+/// Room for a string value after `KEYWORD = ` and between its quotes.
+const MAX_STRING_LEN: usize = CARD_SIZE - 12;
+
+/// What we know about an exposure beyond its pixels.
+#[derive(Debug)]
+pub(crate) struct ExposureMetadata {
+    pub frame: FrameInfo,
+    pub instrument: Option<String>,
+    /// Gain in e-/ADU of the resulting image.
+    pub electrons_per_adu: Option<f64>,
+    /// Offset of the RGGB pattern for Bayer frames.
+    pub bayer_offset: Option<(u32, u32)>,
+    pub bin: u32,
+    /// Subframe origin in binned pixels.
+    pub origin: (u32, u32),
+    /// Camera temperature in °C.
+    pub temperature: Option<f64>,
+}
+
+struct Header(Vec<u8>);
+
+impl Header {
+    fn card(&mut self, keyword: &str, value: &str, comment: &str) {
+        let mut card = format!("{keyword:<8}= {value}");
+        if !comment.is_empty() {
+            card.push_str(" / ");
+            card.push_str(comment);
+        }
+        card.truncate(CARD_SIZE);
+        self.0
+            .extend(format!("{card:<width$}", width = CARD_SIZE).bytes());
+    }
+
+    fn logical(&mut self, keyword: &str, value: bool, comment: &str) {
+        let value = if value { "T" } else { "F" };
+        self.card(keyword, &format!("{value:>20}"), comment);
+    }
+
+    fn int(&mut self, keyword: &str, value: impl Into<i64>, comment: &str) {
+        self.card(keyword, &format!("{:>20}", value.into()), comment);
+    }
+
+    fn float(&mut self, keyword: &str, value: f64, comment: &str) {
+        if value.is_finite() {
+            // Debug formatting always includes a decimal point or an exponent.
+            let value = format!("{value:?}").to_ascii_uppercase();
+            self.card(keyword, &format!("{value:>20}"), comment);
+        }
+    }
+
+    fn string(&mut self, keyword: &str, value: &str, comment: &str) {
+        // Only printable ASCII is allowed, and quotes are escaped by doubling. Cut the escaped
+        // value to fit between the quotes of the card, without splitting an escaped quote.
+        let mut escaped = String::new();
+        for c in value.chars() {
+            let c = match c {
+                ' '..='~' => c,
+                _ => '?',
+            };
+            let len = if c == '\'' { 2 } else { 1 };
+            if escaped.len() + len > MAX_STRING_LEN {
+                break;
+            }
+            escaped.push(c);
+            if c == '\'' {
+                escaped.push(c);
+            }
+        }
+        self.card(keyword, &format!("'{escaped:<8}'"), comment);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.0
+            .extend(format!("{:<width$}", "END", width = CARD_SIZE).bytes());
+        let padded_len = self.0.len().next_multiple_of(BLOCK_SIZE);
+        self.0.resize(padded_len, b' ');
+        self.0
+    }
+}
+
+pub(crate) fn format_date(time: std::time::SystemTime) -> String {
+    let time = time::OffsetDateTime::from(time);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}",
+        time.year(),
+        u8::from(time.month()),
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second(),
+        time.millisecond()
+    )
+}
+
+/// Encode an image with its metadata as a FITS file.
+pub(crate) fn encode(image: &ImageArray, metadata: &ExposureMetadata) -> Vec<u8> {
+    let (width, height, channels) = image.dim();
+    let frame = &metadata.frame;
+
+    let mut header = Header(Vec::new());
+    header.logical("SIMPLE", true, "conforms to FITS standard");
+    // Pixels are stored as signed 16-bit values offset by BZERO.
+    header.int("BITPIX", 16, "array data type");
+    header.int("NAXIS", if channels > 1 { 3 } else { 2 }, "number of axes");
+    header.int("NAXIS1", width as i64, "width");
+    header.int("NAXIS2", height as i64, "height");
+    if channels > 1 {
+        header.int("NAXIS3", channels as i64, "colour channels");
+    }
+    header.int("BZERO", 32768, "offset of unsigned values");
+    header.int("BSCALE", 1, "");
+    header.string("ROWORDER", "TOP-DOWN", "order of image rows");
+    header.string("IMAGETYP", frame.image_type(), "type of exposure");
+    header.float("EXPTIME", frame.exposure, "[s] exposure duration");
+    header.float("EXPOSURE", frame.exposure, "[s] exposure duration");
+    header.string(
+        "DATE-OBS",
+        &format_date(frame.start),
+        "UTC start of exposure",
+    );
+    if let Some(target) = &frame.target {
+        header.string("OBJECT", target, "target name");
+    }
+    if let Some(instrument) = &metadata.instrument {
+        header.string("INSTRUME", instrument, "camera model");
+    }
+    match frame.iso.parse::<i64>() {
+        Ok(iso) => header.int("ISOSPEED", iso, "ISO"),
+        Err(_) => header.string("ISOSPEED", &frame.iso, "ISO"),
+    }
+    if let Some(electrons_per_adu) = metadata.electrons_per_adu {
+        header.float("EGAIN", electrons_per_adu, "[e-/ADU] gain");
+    }
+    header.int("XBINNING", metadata.bin, "binning factor in X");
+    header.int("YBINNING", metadata.bin, "binning factor in Y");
+    header.int("XORGSUBF", metadata.origin.0, "subframe origin in X");
+    header.int("YORGSUBF", metadata.origin.1, "subframe origin in Y");
+    if let Some((x, y)) = metadata.bayer_offset {
+        header.string("BAYERPAT", "RGGB", "Bayer colour pattern");
+        header.int("XBAYROFF", x, "Bayer pattern offset in X");
+        header.int("YBAYROFF", y, "Bayer pattern offset in Y");
+    }
+    if let Some(temperature) = metadata.temperature {
+        header.float("CCD-TEMP", temperature, "[C] camera temperature");
+    }
+
+    let mut fits = header.finish();
+    fits.reserve(width * height * channels * 2 + BLOCK_SIZE);
+    for channel in 0..channels {
+        for y in 0..height {
+            for x in 0..width {
+                let value = image[[x, y, channel]].clamp(0, u16::MAX.into()) - 32768;
+                fits.extend((value as i16).to_be_bytes());
+            }
+        }
+    }
+    let padded_len = fits.len().next_multiple_of(BLOCK_SIZE);
+    fits.resize(padded_len, 0);
+    fits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse the string value of a single header card, checking that it's properly quoted.
+    fn parse_string(card: &[u8]) -> String {
+        assert_eq!(card.len(), CARD_SIZE);
+        let card = std::str::from_utf8(card).unwrap();
+        let mut chars = card[10..]
+            .strip_prefix('\'')
+            .expect("missing opening quote")
+            .chars();
+        let mut value = String::new();
+        loop {
+            match chars.next().expect("missing closing quote") {
+                '\'' if chars.as_str().starts_with('\'') => {
+                    chars.next();
+                    value.push('\'');
+                }
+                '\'' => break,
+                c => value.push(c),
+            }
+        }
+        let rest = chars.as_str().trim_start();
+        assert!(
+            rest.is_empty() || rest.starts_with('/'),
+            "garbage after value: {rest:?}"
+        );
+        value.trim_end().to_owned()
+    }
+
+    fn string_card(value: &str, comment: &str) -> Vec<u8> {
+        let mut header = Header(Vec::new());
+        header.string("OBJECT", value, comment);
+        header.0
+    }
+
+    #[test]
+    fn short_string() {
+        let card = string_card("M 31", "target");
+        assert_eq!(
+            std::str::from_utf8(&card).unwrap().trim_end(),
+            "OBJECT  = 'M 31    ' / target"
+        );
+    }
+
+    #[test]
+    fn escapes_value() {
+        assert_eq!(
+            parse_string(&string_card("Bode's Galaxy", "")),
+            "Bode's Galaxy"
+        );
+        assert_eq!(parse_string(&string_card("Ω Cen", "")), "? Cen");
+    }
+
+    #[test]
+    fn cuts_long_value_after_escaping() {
+        let value = "x".repeat(100);
+        assert_eq!(
+            parse_string(&string_card(&value, "comment")),
+            "x".repeat(MAX_STRING_LEN)
+        );
+        // Each repetition takes 3 characters escaped, leaving room for one more "a" only.
+        let value = "a'".repeat(40);
+        let expected = format!("{}a", "a'".repeat(MAX_STRING_LEN / 3));
+        assert_eq!(parse_string(&string_card(&value, "")), expected);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::backend::{CameraBackend, CameraEvent, CameraFile, RadioConfig};
+use async_trait::async_trait;
+use bytes::Bytes;
+use gphoto2::list::CameraDescriptor;
+use gphoto2::widget::{RadioWidget, TextWidget, ToggleWidget, Widget, WidgetBase};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::Instrument;
+
+/// A singleton context for gphoto2 - we always need one throughout this app's lifetime,
+/// so it's easier to store it in a static variable rather than keep passing it around.
+fn gphoto2_context() -> &'static gphoto2::Context {
+    static CONTEXT: OnceLock<gphoto2::Context> = OnceLock::new();
+    CONTEXT.get_or_init(|| gphoto2::Context::new().unwrap())
+}
+
+pub(crate) async fn list_cameras() -> eyre::Result<impl Iterator<Item = CameraDescriptor>> {
+    Ok(gphoto2_context().list_cameras().await?)
+}
+
+/// Config widgets some cameras use to report their temperature.
+const TEMPERATURE_WIDGETS: [&str; 3] = ["sensortemperature", "cameratemperature", "temperature"];
+
+#[derive(Debug)]
+enum BulbWidget {
+    Standard(ToggleWidget),
+    EosRemoteRelease(RadioWidget),
+}
+
+/// Camera controlled via gphoto2.
+#[derive(custom_debug::Debug)]
+pub(crate) struct Gphoto2Camera {
+    #[debug(skip)]
+    camera: gphoto2::Camera,
+    bulb: Option<BulbWidget>,
+    /// Config widget reporting the camera temperature, if any.
+    temperature_widget: Option<&'static str>,
+    /// Radio widgets read so far, so that changing them doesn't need another round trip.
+    #[debug(skip)]
+    radio_widgets: parking_lot::Mutex<HashMap<String, RadioWidget>>,
+}
+
+impl Gphoto2Camera {
+    pub async fn open(descriptor: &CameraDescriptor) -> eyre::Result<Self> {
+        let camera = gphoto2_context().get_camera(descriptor).await?;
+
+        let bulb = if let Ok(toggle) = camera.config_key("bulb").await {
+            Some(BulbWidget::Standard(toggle))
+        } else if let Ok(radio) = camera.config_key("eosremoterelease").await {
+            Some(BulbWidget::EosRemoteRelease(radio))
+        } else {
+            None
+        };
+
+        let mut temperature_widget = None;
+        for name in TEMPERATURE_WIDGETS {
+            if camera.config_key::<Widget>(name).await.is_ok() {
+                temperature_widget = Some(name);
+                break;
+            }
+        }
+
+        Ok(Self {
+            camera,
+            bulb,
+            temperature_widget,
+            radio_widgets: Default::default(),
+        })
+    }
+
+    /// Prepare the bulb widget for switching `on` or off.
+    fn bulb_widget(&self, on: bool) -> eyre::Result<&WidgetBase> {
+        Ok(match &self.bulb {
+            Some(BulbWidget::Standard(toggle)) => {
+                toggle.set_toggled(on);
+                toggle
+            }
+            Some(BulbWidget::EosRemoteRelease(radio)) => {
+                radio.set_choice(if on { "Immediate" } else { "Release Full" })?;
+                radio
+            }
+            None => eyre::bail!("Camera does not support bulb exposures"),
+        })
+    }
+}
+
+impl From<gphoto2::file::CameraFilePath> for CameraFile {
+    fn from(path: gphoto2::file::CameraFilePath) -> Self {
+        Self {
+            folder: path.folder().into_owned(),
+            name: path.name().into_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl CameraBackend for Gphoto2Camera {
+    async fn radio_config(&self, name: &str) -> eyre::Result<RadioConfig> {
+        let widget: RadioWidget = self.camera.config_key(name).await?;
+        let config = RadioConfig {
+            choices: widget.choices_iter().collect(),
+            current: widget.choice(),
+        };
+        self.radio_widgets.lock().insert(name.to_owned(), widget);
+        Ok(config)
+    }
+
+    async fn set_radio_config(&self, name: &str, value: &str) -> eyre::Result<()> {
+        let cached = self.radio_widgets.lock().get(name).cloned();
+        let widget = match cached {
+            Some(widget) => widget,
+            None => self.camera.config_key(name).await?,
+        };
+        widget.set_choice(value)?;
+        self.camera.set_config(&widget).await?;
+        Ok(())
+    }
+
+    /// Read the serial number from the config widgets or, failing that, the summary text.
+    async fn serial_number(&self) -> Option<String> {
+        fn valid(value: &str) -> Option<String> {
+            let value = value.trim();
+            // Some cameras report all zeroes when they don't have a serial number to report.
+            (!value.is_empty() && value.bytes().any(|b| b != b'0')).then(|| value.to_owned())
+        }
+
+        for key in ["serialnumber", "eosserialnumber"] {
+            if let Ok(widget) = self.camera.config_key::<TextWidget>(key).await {
+                if let Some(serial_number) = valid(&widget.value()) {
+                    return Some(serial_number);
+                }
+            }
+        }
+
+        self.camera
+            .summary()
+            .ok()?
+            .lines()
+            .find_map(|line| valid(line.trim().strip_prefix("Serial Number:")?))
+    }
+
+    fn supports_bulb(&self) -> bool {
+        self.bulb.is_some()
+    }
+
+    async fn set_bulb(&self, on: bool) -> eyre::Result<()> {
+        self.camera.set_config(self.bulb_widget(on)?).await?;
+        Ok(())
+    }
+
+    fn set_bulb_blocking(&self, on: bool) -> eyre::Result<()> {
+        self.camera.set_config(self.bulb_widget(on)?).wait()?;
+        Ok(())
+    }
+
+    fn has_temperature(&self) -> bool {
+        self.temperature_widget.is_some()
+    }
+
+    async fn temperature(&self) -> eyre::Result<f64> {
+        let name = self
+            .temperature_widget
+            .ok_or_else(|| eyre::eyre!("Camera doesn't report its temperature"))?;
+        Ok(match self.camera.config_key::<Widget>(name).await? {
+            Widget::Range(widget) => widget.value().into(),
+            Widget::Text(widget) => {
+                let value = widget.value();
+                // Strip units like "°C".
+                value
+                    .trim()
+                    .trim_end_matches(|c: char| !c.is_ascii_digit())
+                    .parse()
+                    .map_err(|_| eyre::eyre!("Couldn't parse temperature {value:?}"))?
+            }
+            _ => eyre::bail!("Unsupported type of temperature widget {name}"),
+        })
+    }
+
+    async fn capture(&self) -> eyre::Result<CameraFile> {
+        Ok(self.camera.capture_image().await?.into())
+    }
+
+    async fn wait_event(&self, timeout: Duration) -> eyre::Result<CameraEvent> {
+        Ok(match self.camera.wait_event(timeout).await? {
+            gphoto2::camera::CameraEvent::NewFile(path) => CameraEvent::NewFile(path.into()),
+            gphoto2::camera::CameraEvent::Timeout => CameraEvent::Timeout,
+            event => CameraEvent::Other(format!("{event:?}")),
+        })
+    }
+
+    async fn download(&self, file: &CameraFile) -> eyre::Result<Bytes> {
+        async {
+            let camera_file = self.camera.fs().download(&file.folder, &file.name).await?;
+
+            let data = camera_file.get_data(gphoto2_context()).await?;
+
+            Ok(data.into())
+        }
+        .instrument(
+            tracing::error_span!("download_file", folder = ?file.folder, filename = ?file.name),
+        )
+        .await
+    }
+
+    async fn delete(&self, file: &CameraFile) -> eyre::Result<()> {
+        self.camera
+            .fs()
+            .delete_file(&file.folder, &file.name)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,33 @@
+mod actions;
+mod archive;
+mod ascom_camera;
+mod backend;
+mod binning;
+mod bulb_calibration;
+mod bulb_control;
+mod cached_radio_widget;
+mod camera;
+mod capture;
+mod config;
+mod convert_image;
+mod demosaic;
+mod device;
+mod device_ids;
+mod dimensions;
+mod discovery;
+mod driver;
+mod error;
+mod export;
+mod exposure;
+mod fits;
+mod gphoto2_backend;
+mod makernotes;
+mod parse_image;
+mod sensors;
+mod shutter_speed;
+mod simulator;
+mod state_file;
+mod xisf;
+
+pub use config::Config;
+pub use driver::Driver;
@@ -0,0 +1,123 @@
+use exif::{Exif, In, Tag, Value};
+
+/// Byte-order aware reader over the TIFF structure of Exif data.
+struct TiffData<'a> {
+    buf: &'a [u8],
+    little_endian: bool,
+}
+
+impl TiffData<'_> {
+    fn bytes<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        self.buf
+            .get(offset..offset.checked_add(N)?)?
+            .try_into()
+            .ok()
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.bytes(offset)?;
+        Some(match self.little_endian {
+            true => u16::from_le_bytes(bytes),
+            false => u16::from_be_bytes(bytes),
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.bytes(offset)?;
+        Some(match self.little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    }
+
+    /// Find `tag` in the IFD at `ifd_offset` and return the offset of its value.
+    fn find_entry(&self, ifd_offset: usize, tag: u16) -> Option<usize> {
+        let count = self.u16(ifd_offset)?;
+        (0..usize::from(count)).find_map(|i| {
+            let entry = ifd_offset + 2 + i * 12;
+            if self.u16(entry)? != tag {
+                return None;
+            }
+            let value_size = match self.u16(entry + 2)? {
+                // BYTE, ASCII, SBYTE, UNDEFINED
+                1 | 2 | 6 | 7 => 1,
+                // SHORT, SSHORT
+                3 | 8 => 2,
+                // LONG, SLONG, FLOAT
+                4 | 9 | 11 => 4,
+                _ => 8,
+            } * self.u32(entry + 4)? as usize;
+            // Small values are stored inline, others are referenced by offset from the TIFF header.
+            Some(match value_size {
+                0..=4 => entry + 8,
+                _ => self.u32(entry + 8)? as usize,
+            })
+        })
+    }
+}
+
+/// Canon stores camera temperature in ShotInfo (tag 0x0004) at index 12, offset by 128.
+fn canon_temperature(data: &TiffData, makernote_offset: usize) -> Option<f64> {
+    let shot_info = data.find_entry(makernote_offset, 0x0004)?;
+    let value = data.u16(shot_info + 12 * 2)? as i16;
+    // Zero means the model doesn't record temperature.
+    (value != 0).then(|| f64::from(value) - 128.)
+}
+
+/// Sony maker note values are obfuscated with a simple substitution cipher.
+fn sony_decipher(byte: u8) -> u8 {
+    if byte >= 249 {
+        return byte;
+    }
+    // Enciphering is `b^3 mod 249`, so the inverse is `b^55 mod 249`.
+    (0..55).fold(1_u32, |acc, _| acc * u32::from(byte) % 249) as u8
+}
+
+/// Sony stores temperature in the enciphered tag 0x9402.
+fn sony_temperature(data: &TiffData, makernote_offset: usize) -> Option<f64> {
+    // Some models prefix the IFD with a header.
+    let ifd_offset = match data.buf.get(makernote_offset..makernote_offset + 12)? {
+        b"SONY DSC \0\0\0" | b"SONY CAM \0\0\0" => makernote_offset + 12,
+        _ => makernote_offset,
+    };
+    let tag = data.find_entry(ifd_offset, 0x9402)?;
+    let [.., test, _, temperature] = data.bytes::<5>(tag)?.map(sony_decipher);
+    // The temperature is only valid if this marker byte is set.
+    (test == 255).then(|| f64::from(temperature as i8))
+}
+
+/// Camera temperature in °C recorded in the image metadata, if any.
+///
+/// Nikon bodies store temperature in encrypted maker notes, so it's not supported here;
+/// for them, the temperature config widget is read after each download instead, if there is one.
+pub(crate) fn camera_temperature(exif: &Exif) -> Option<f64> {
+    if let Some(field) = exif.get_field(Tag::Temperature, In::PRIMARY) {
+        if let Value::SRational(value) = &field.value {
+            if let Some(value) = value.first().filter(|value| value.denom != 0) {
+                return Some(value.to_f64());
+            }
+        }
+    }
+
+    let make = match &exif.get_field(Tag::Make, In::PRIMARY)?.value {
+        Value::Ascii(make) => String::from_utf8_lossy(make.first()?).into_owned(),
+        _ => return None,
+    };
+    let Value::Undefined(_, makernote_offset) = &exif.get_field(Tag::MakerNote, In::PRIMARY)?.value
+    else {
+        return None;
+    };
+    let data = TiffData {
+        buf: exif.buf(),
+        little_endian: exif.little_endian(),
+    };
+    let makernote_offset = *makernote_offset as usize;
+
+    if make.starts_with("Canon") {
+        canon_temperature(&data, makernote_offset)
+    } else if make.starts_with("SONY") {
+        sony_temperature(&data, makernote_offset)
+    } else {
+        None
+    }
+}
@@ -1,13 +1,66 @@
+use crate::error::DriverError;
+use crate::makernotes;
 use bytes::Bytes;
 use eyre::ContextCompat;
 use image::math::Rect;
 use image::{DynamicImage, Luma};
-use rawler::RawlerError;
+use rawler::{RawlerError, CFA};
+use serde::Deserialize;
+
+/// Offset of the RGGB pattern within `cfa`, in the sense of ASCOM `BayerOffsetX`/`BayerOffsetY`,
+/// or `None` if it's not a 2x2 RGB Bayer pattern.
+pub fn bayer_offset(cfa: &CFA) -> Option<(u32, u32)> {
+    let rggb = CFA::new("RGGB");
+    [(0, 0), (1, 0), (0, 1), (1, 1)]
+        .into_iter()
+        .find(|&(x, y)| rggb.shift(x, y).name == cfa.name)
+        .map(|(x, y)| (x as u32, y as u32))
+}
+
+/// How RAW pixel values are adjusted for black and white levels.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RawLevels {
+    /// Keep values as recorded by the camera.
+    #[default]
+    Native,
+    /// Subtract the black level.
+    SubtractBlack,
+    /// Subtract the black level and stretch the white level to the full 16-bit range.
+    Rescale,
+}
+
+/// Sensor levels of a RAW frame.
+#[derive(Debug, Clone, Copy)]
+pub struct Levels {
+    /// Black level for each position of the 2x2 pattern at the image origin, row by row.
+    pub black: [u16; 4],
+    /// Saturation level.
+    pub white: u16,
+    pub bits_per_sample: u32,
+}
+
+impl Levels {
+    /// Average black level across the pattern.
+    pub fn mean_black(&self) -> f32 {
+        self.black.iter().copied().map(f32::from).sum::<f32>() / 4.
+    }
+}
 
 pub struct ImgWithMetadata {
     pub image: DynamicImage,
     pub crop_area: Rect,
+    /// Colour filter pattern at the image origin for Bayer RAW frames.
+    pub cfa: Option<CFA>,
+    /// As-shot white balance multipliers for RGB, normalised to green.
+    pub wb_coeffs: Option<[f32; 3]>,
+    /// Black and white levels for RAW frames.
+    pub levels: Option<Levels>,
+    /// Maximum pixel value the image can contain.
+    pub max_adu: u32,
     pub exposure_time: Option<f64>,
+    /// Camera temperature in °C, if recorded in the metadata.
+    pub temperature: Option<f64>,
 }
 
 impl ImgWithMetadata {
@@ -21,24 +74,80 @@ impl ImgWithMetadata {
                     .exposure_time
                     .map(|r| f64::from(r.n) / f64::from(r.d));
                 let raw_image = decoder.raw_image(&mut raw_file, Default::default(), false)?;
-                let cfa = raw_image.cfa.to_string();
-                eyre::ensure!(cfa == "RGGB", "Unsupported Bayer pattern: {cfa}");
+                // Rawler doesn't parse maker notes, so read them separately where possible
+                // (e.g. for TIFF-based formats like CR2, NEF and ARW).
+                let temperature = exif::Reader::new()
+                    .read_from_container(&mut std::io::Cursor::new(&data[..]))
+                    .ok()
+                    .and_then(|exif| makernotes::camera_temperature(&exif));
+                let cfa = raw_image.camera.cfa.clone();
+                if bayer_offset(&cfa).is_none() {
+                    return Err(eyre::Report::new(DriverError::UnsupportedFile)
+                        .wrap_err(format!("Unsupported Bayer pattern: {}", cfa.name)));
+                }
+                let wb_coeffs = match raw_image.wb_coeffs {
+                    [r, g, b, _] if [r, g, b].iter().all(|c| c.is_finite()) && g > 0. => {
+                        Some([r / g, 1., b / g])
+                    }
+                    _ => None,
+                };
                 let width = raw_image.width as u32;
                 let height = raw_image.height as u32;
-                Ok(ImgWithMetadata {
-                    image: match raw_image.data {
-                        rawler::RawImageData::Float(_) => {
-                            // See https://github.com/image-rs/image/issues/1940.
-                            // Might use our own enum if it turns out that any popular DSLRs use this format
-                            // (I'm not aware of any though).
-                            eyre::bail!("Floating-point raw files are unsupported")
-                        }
-                        rawler::RawImageData::Integer(img_data) => {
-                            image::ImageBuffer::<Luma<u16>, _>::from_vec(width, height, img_data)
-                                .context("couldn't match dimensions to raw data")?
-                                .into()
+                let (image, levels) = match raw_image.data {
+                    rawler::RawImageData::Float(img_data) => {
+                        // Neither `image` (https://github.com/image-rs/image/issues/1940) nor Alpaca
+                        // image arrays support floating-point luma, so quantize into the full u16 range
+                        // between black and white levels instead.
+                        let black = raw_image.blacklevel.as_bayer_array();
+                        let white = raw_image.whitelevel.as_bayer_array();
+                        let img_data = img_data
+                            .iter()
+                            .enumerate()
+                            .map(|(i, &value)| {
+                                let (x, y) = (i % raw_image.width, i / raw_image.width);
+                                let idx = (y % 2) * 2 + x % 2;
+                                let (black, white) = match (black[idx], white[idx]) {
+                                    (black, white) if white > black => (black, white),
+                                    // Levels are missing, assume the data is normalised.
+                                    _ => (0., 1.),
+                                };
+                                ((value - black) / (white - black) * f32::from(u16::MAX)).round()
+                                    as u16
+                            })
+                            .collect();
+                        (
+                            img_data,
+                            Levels {
+                                black: [0; 4],
+                                white: u16::MAX,
+                                bits_per_sample: 16,
+                            },
+                        )
+                    }
+                    rawler::RawImageData::Integer(img_data) => {
+                        let bits_per_sample = raw_image.bps as u32;
+                        let mut white = raw_image
+                            .whitelevel
+                            .as_bayer_array()
+                            .into_iter()
+                            .fold(0., f32::max) as u16;
+                        if (1..16).contains(&bits_per_sample) {
+                            white = white.min((1 << bits_per_sample) - 1);
                         }
-                    },
+                        (
+                            img_data,
+                            Levels {
+                                black: raw_image.blacklevel.as_bayer_array().map(|b| b as u16),
+                                white,
+                                bits_per_sample,
+                            },
+                        )
+                    }
+                };
+                Ok(ImgWithMetadata {
+                    image: image::ImageBuffer::<Luma<u16>, _>::from_vec(width, height, image)
+                        .context("couldn't match dimensions to raw data")?
+                        .into(),
                     crop_area: match raw_image.crop_area {
                         Some(crop_area) => Rect {
                             x: crop_area.x() as u32,
@@ -53,28 +162,42 @@ impl ImgWithMetadata {
                             height,
                         },
                     },
+                    cfa: Some(cfa),
+                    wb_coeffs,
+                    levels: Some(levels),
+                    max_adu: levels.white.into(),
                     exposure_time,
+                    temperature,
                 })
             }
             Err(RawlerError::Unsupported { .. }) => {
                 let image = image::load_from_memory(&data)?;
 
-                let exposure_time = match exif::Reader::new()
+                let color = image.color();
+                let max_adu =
+                    u32::MAX >> (32 - color.bits_per_pixel() / u16::from(color.channel_count()));
+
+                let exif = match exif::Reader::new()
                     .read_from_container(&mut std::io::Cursor::new(data))
                 {
-                    Ok(exif) => exif
-                        .get_field(exif::Tag::ExposureTime, exif::In::PRIMARY)
-                        .map(|field| match &field.value {
-                            exif::Value::Rational(rational) if rational.len() == 1 => {
-                                Ok(rational[0].to_f64())
-                            }
-                            v => eyre::bail!("Invalid field type for exposure time: {v:?}"),
-                        })
-                        .transpose()?,
+                    Ok(exif) => Some(exif),
                     Err(exif::Error::NotFound(_)) => None,
                     Err(err) => return Err(err.into()),
                 };
 
+                let exposure_time = exif
+                    .as_ref()
+                    .and_then(|exif| exif.get_field(exif::Tag::ExposureTime, exif::In::PRIMARY))
+                    .map(|field| match &field.value {
+                        exif::Value::Rational(rational) if rational.len() == 1 => {
+                            Ok(rational[0].to_f64())
+                        }
+                        v => eyre::bail!("Invalid field type for exposure time: {v:?}"),
+                    })
+                    .transpose()?;
+
+                let temperature = exif.as_ref().and_then(makernotes::camera_temperature);
+
                 Ok(ImgWithMetadata {
                     crop_area: Rect {
                         x: 0,
@@ -83,10 +206,53 @@ impl ImgWithMetadata {
                         height: image.height(),
                     },
                     image,
+                    cfa: None,
+                    wb_coeffs: None,
+                    levels: None,
+                    max_adu,
                     exposure_time,
+                    temperature,
                 })
             }
             Err(err) => Err(err.into()),
         }
     }
+
+    /// Adjust RAW pixel values for black and white levels. Other images are left intact.
+    ///
+    /// Returns the number of resulting ADU per original ADU.
+    pub fn apply_levels(&mut self, mode: RawLevels) -> f64 {
+        let (Some(levels), DynamicImage::ImageLuma16(img)) = (&mut self.levels, &mut self.image)
+        else {
+            return 1.;
+        };
+        let scale = match mode {
+            RawLevels::Native => return 1.,
+            RawLevels::SubtractBlack => 1.,
+            RawLevels::Rescale => {
+                f64::from(u16::MAX) / (f64::from(levels.white) - f64::from(levels.mean_black()))
+            }
+        };
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let black = levels.black[((y % 2) * 2 + x % 2) as usize];
+            let value = pixel.0[0].saturating_sub(black);
+            pixel.0[0] = match mode {
+                RawLevels::Rescale => {
+                    let range = levels.white.saturating_sub(black).max(1);
+                    (f32::from(value) / f32::from(range) * f32::from(u16::MAX)).round() as u16
+                }
+                _ => value,
+            };
+        }
+        levels.white = match mode {
+            RawLevels::Rescale => u16::MAX,
+            // Channels with the highest black level saturate first.
+            _ => levels
+                .white
+                .saturating_sub(levels.black.into_iter().max().unwrap_or(0)),
+        };
+        levels.black = [0; 4];
+        self.max_adu = levels.white.into();
+        scale
+    }
 }
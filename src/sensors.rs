@@ -0,0 +1,96 @@
+use crate::camera::Size;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+/// Sensor characteristics at a particular ISO.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct IsoInfo {
+    /// Gain in e-/ADU.
+    pub gain: Option<f64>,
+    /// Read noise in e-.
+    pub read_noise: Option<f64>,
+}
+
+/// Sensor characteristics of a camera model. See `sensors.toml` for the meaning of fields.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SensorInfo {
+    pub name: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub pixel_size: Option<f64>,
+    pub full_well: Option<f64>,
+    pub iso: BTreeMap<String, IsoInfo>,
+}
+
+impl SensorInfo {
+    /// Fill in any unset fields from `defaults`.
+    fn or(mut self, defaults: &SensorInfo) -> Self {
+        for (iso, defaults) in &defaults.iso {
+            let info = self.iso.entry(iso.clone()).or_default();
+            info.gain = info.gain.or(defaults.gain);
+            info.read_noise = info.read_noise.or(defaults.read_noise);
+        }
+        Self {
+            name: self.name.or_else(|| defaults.name.clone()),
+            width: self.width.or(defaults.width),
+            height: self.height.or(defaults.height),
+            pixel_size: self.pixel_size.or(defaults.pixel_size),
+            full_well: self.full_well.or(defaults.full_well),
+            iso: self.iso,
+        }
+    }
+
+    pub fn dimensions(&self) -> Option<Size> {
+        Some(Size {
+            width: self.width?,
+            height: self.height?,
+        })
+    }
+
+    pub fn at_iso(&self, iso: &str) -> IsoInfo {
+        self.iso.get(iso).copied().unwrap_or_default()
+    }
+
+    /// Check that configured values are physically meaningful.
+    pub fn validate(&self) -> eyre::Result<()> {
+        let values = [
+            ("pixel_size", self.pixel_size),
+            ("full_well", self.full_well),
+        ]
+        .into_iter()
+        .chain(
+            self.iso
+                .values()
+                .flat_map(|info| [("gain", info.gain), ("read_noise", info.read_noise)]),
+        );
+        for (name, value) in values {
+            if let Some(value) = value {
+                eyre::ensure!(
+                    value.is_finite() && value > 0.,
+                    "Sensor {name} must be a positive number, got {value}"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn builtin() -> &'static BTreeMap<String, SensorInfo> {
+    static SENSORS: OnceLock<BTreeMap<String, SensorInfo>> = OnceLock::new();
+    SENSORS.get_or_init(|| {
+        toml::from_str(include_str!("sensors.toml")).expect("built-in sensor database is invalid")
+    })
+}
+
+/// Look up sensor characteristics for a camera model, with user-provided `overrides` taking
+/// precedence over the built-in database.
+pub(crate) fn lookup(overrides: &BTreeMap<String, SensorInfo>, model: &str) -> SensorInfo {
+    let builtin = builtin().get(model).cloned().unwrap_or_default();
+    match overrides.get(model) {
+        Some(info) => info.clone().or(&builtin),
+        None => builtin,
+    }
+}
@@ -0,0 +1,134 @@
+use crate::backend::CameraBackend;
+use crate::cached_radio_widget::CachedRadioWidget;
+use std::time::Duration;
+
+/// Relative difference within which a requested duration is considered to match a shutter speed.
+const MATCH_TOLERANCE: f64 = 0.05;
+
+/// How an exposure is taken.
+#[derive(Debug, Clone)]
+pub(crate) enum ExposureMechanism {
+    /// Capture with the camera timing the exposure at a discrete shutter speed.
+    Timed { choice: String, duration: Duration },
+    /// Hold the shutter open via bulb control for the requested duration.
+    Bulb,
+}
+
+/// Discrete shutter speeds offered by the camera's `shutterspeed` widget.
+#[derive(Debug)]
+pub(crate) struct ShutterSpeeds {
+    widget: CachedRadioWidget,
+    /// Choices with their durations, from shortest to longest.
+    speeds: Vec<(String, Duration)>,
+    /// Choice that hands exposure timing over to bulb control, if listed.
+    bulb: Option<String>,
+}
+
+/// Parse shutter speed choices like "1/4000", "0.5", "30" or "30s".
+pub(crate) fn parse_speed(choice: &str) -> Option<Duration> {
+    let choice = choice.trim().trim_end_matches('s');
+    let seconds = match choice.split_once('/') {
+        Some((num, den)) => num.trim().parse::<f64>().ok()? / den.trim().parse::<f64>().ok()?,
+        None => choice.parse().ok()?,
+    };
+    Duration::try_from_secs_f64(seconds)
+        .ok()
+        .filter(|duration| !duration.is_zero())
+}
+
+impl ShutterSpeeds {
+    /// Read the shutter speed widget, or `None` if the camera doesn't expose discrete speeds.
+    pub async fn new(camera: &dyn CameraBackend) -> Option<Self> {
+        let widget = match CachedRadioWidget::load(camera, "shutterspeed").await {
+            Ok(widget) => widget,
+            Err(err) => {
+                tracing::debug!(%err, "No shutter speed widget, using bulb for all exposures");
+                return None;
+            }
+        };
+        let mut speeds = widget
+            .choices()
+            .iter()
+            .filter_map(|choice| Some((choice.clone(), parse_speed(choice)?)))
+            .collect::<Vec<_>>();
+        if speeds.is_empty() {
+            tracing::debug!(choices = ?widget.choices(), "No discrete shutter speeds available");
+            return None;
+        }
+        speeds.sort_by_key(|&(_, duration)| duration);
+        let bulb = widget
+            .choices()
+            .iter()
+            .find(|choice| choice.eq_ignore_ascii_case("bulb"))
+            .cloned();
+        Some(Self {
+            widget,
+            speeds,
+            bulb,
+        })
+    }
+
+    pub fn shortest(&self) -> Duration {
+        self.speeds[0].1
+    }
+
+    /// Largest gap between consecutive shutter speeds that durations shorter than `limit` snap to.
+    pub fn largest_step(&self, limit: Duration) -> Duration {
+        self.speeds
+            .windows(2)
+            .filter(|pair| pair[0].1 < limit)
+            .map(|pair| pair[1].1 - pair[0].1)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Pick how to expose for `duration`.
+    ///
+    /// Durations matching a shutter speed, as well as anything shorter than `min_bulb` (where
+    /// USB latency would dominate bulb timing), use the closest speed; everything else uses bulb.
+    pub fn mechanism(&self, duration: Duration, min_bulb: Duration) -> ExposureMechanism {
+        let requested = duration.max(self.shortest()).as_secs_f64();
+        let distance = |speed: &Duration| (speed.as_secs_f64() / requested).ln().abs();
+        let (choice, speed) = self
+            .speeds
+            .iter()
+            .min_by(|(_, a), (_, b)| distance(a).total_cmp(&distance(b)))
+            .expect("shutter speeds are never empty");
+        if (speed.as_secs_f64() / requested - 1.).abs() <= MATCH_TOLERANCE || duration < min_bulb {
+            ExposureMechanism::Timed {
+                choice: choice.clone(),
+                duration: *speed,
+            }
+        } else {
+            ExposureMechanism::Bulb
+        }
+    }
+
+    /// Select the widget choice for `mechanism`, returning the widget to apply to the camera.
+    pub fn select(&self, mechanism: &ExposureMechanism) -> eyre::Result<&CachedRadioWidget> {
+        match mechanism {
+            ExposureMechanism::Timed { choice, .. } => self.widget.set_choice(choice)?,
+            ExposureMechanism::Bulb => {
+                if let Some(bulb) = &self.bulb {
+                    self.widget.set_choice(bulb)?;
+                }
+            }
+        }
+        Ok(&self.widget)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_speed("1/4000"), Some(Duration::from_micros(250)));
+        assert_eq!(parse_speed("0.5"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_speed("30s"), Some(Duration::from_secs(30)));
+        for invalid in ["bulb", "", "0", "1/0", "-1"] {
+            assert_eq!(parse_speed(invalid), None, "{invalid:?}");
+        }
+    }
+}
@@ -0,0 +1,497 @@
+use crate::backend::{CameraBackend, CameraEvent, CameraFile, RadioConfig};
+use crate::config::{SimulatedFailure, SimulatorConfig};
+use crate::error::DriverError;
+use crate::shutter_speed::parse_speed;
+use async_trait::async_trait;
+use bytes::Bytes;
+use rawler::dng::writer::DngWriter;
+use rawler::dng::{CropMode, DngCompression, DngPhotometricConversion, DNG_VERSION_V1_4};
+use rawler::rawimage::{BlackLevel, CFAConfig, RawImage, RawPhotometricInterpretation, WhiteLevel};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+const FOLDER: &str = "/DCIM/100SIMUL";
+const BLACK_LEVEL: u16 = 2048;
+const WHITE_LEVEL: u16 = (1 << 14) - 1;
+/// Response of red, green and blue pixels, so that frames need white balancing like real ones.
+const CHANNEL_RESPONSE: [f64; 3] = [0.5, 1., 0.7];
+/// Standard deviation of the star profile in pixels.
+const STAR_SIGMA: f64 = 1.5;
+
+const ISO_CHOICES: &[&str] = &["100", "200", "400", "800", "1600", "3200", "6400"];
+const IMAGE_FORMAT_CHOICES: &[&str] = &["RAW", "RAW + Large Fine JPEG", "Large Fine JPEG"];
+const SHUTTER_SPEED_CHOICES: &[&str] = &[
+    "bulb", "30", "15", "8", "4", "2", "1", "1/2", "1/4", "1/8", "1/15", "1/30", "1/60", "1/125",
+    "1/250", "1/500", "1/1000", "1/2000", "1/4000",
+];
+
+fn radio_choices(name: &str) -> Option<&'static [&'static str]> {
+    Some(match name {
+        "iso" => ISO_CHOICES,
+        "imageformat" => IMAGE_FORMAT_CHOICES,
+        "shutterspeed" => SHUTTER_SPEED_CHOICES,
+        _ => return None,
+    })
+}
+
+/// SplitMix64, good enough for noise and star positions without pulling in a dependency.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed in `[0, 1)`.
+    fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
+    }
+
+    /// Standard normal distribution via the Box-Muller transform.
+    fn normal(&mut self) -> f64 {
+        let radius = (-2. * (1. - self.uniform()).ln()).sqrt();
+        radius * (std::f64::consts::TAU * self.uniform()).cos()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Star {
+    x: f64,
+    y: f64,
+    /// Peak brightness in ADU per second at ISO 100.
+    flux: f64,
+}
+
+struct State {
+    rng: Rng,
+    settings: HashMap<String, String>,
+    /// When the shutter was opened for a bulb exposure, and whether that capture is to be corrupted.
+    bulb_opened: Option<(Instant, bool)>,
+    /// Files on the card, with whether they fail to download.
+    files: HashMap<String, (Bytes, bool)>,
+    events: VecDeque<CameraFile>,
+    next_file_number: u32,
+    /// Set once a simulated disconnect happens; the camera has to be reopened afterwards.
+    disconnected: bool,
+}
+
+/// Camera that renders a synthetic star field instead of talking to hardware.
+#[derive(custom_debug::Debug)]
+pub(crate) struct SimulatedCamera {
+    index: usize,
+    config: Arc<SimulatorConfig>,
+    #[debug(skip)]
+    stars: Vec<Star>,
+    #[debug(skip)]
+    state: parking_lot::Mutex<State>,
+}
+
+/// `GP_ERROR_IO_USB_FIND`, which libgphoto2 reports once a camera is unplugged.
+const GP_ERROR_IO_USB_FIND: std::os::raw::c_int = -52;
+
+fn disconnected_error() -> eyre::Report {
+    gphoto2::Error::new(
+        GP_ERROR_IO_USB_FIND,
+        Some("Simulated camera was disconnected".to_owned()),
+    )
+    .into()
+}
+
+impl SimulatedCamera {
+    pub fn new(index: usize, config: Arc<SimulatorConfig>) -> Self {
+        // Keep the star field stable for the same camera, even across reconnections.
+        let mut rng = Rng(config.seed.unwrap_or_default() ^ index as u64);
+        let stars = (0..config.stars)
+            .map(|_| Star {
+                x: rng.uniform() * f64::from(config.width),
+                y: rng.uniform() * f64::from(config.height),
+                // Log-uniform between 50 and 5000, so that faint stars are the most common.
+                flux: 50. * 100_f64.powf(rng.uniform()),
+            })
+            .collect();
+        let noise_seed = config.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64
+        });
+        let settings = [
+            ("iso", "800"),
+            ("imageformat", "RAW"),
+            ("shutterspeed", "1"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect();
+        Self {
+            index,
+            stars,
+            state: parking_lot::Mutex::new(State {
+                rng: Rng(noise_seed ^ (index as u64).rotate_left(32)),
+                settings,
+                bulb_opened: None,
+                files: HashMap::new(),
+                events: VecDeque::new(),
+                next_file_number: 1,
+                disconnected: false,
+            }),
+            config,
+        }
+    }
+
+    fn latency(&self) -> Duration {
+        Duration::from_secs_f64(self.config.latency)
+    }
+
+    fn check_connected(&self) -> eyre::Result<()> {
+        match self.state.lock().disconnected {
+            true => Err(disconnected_error()),
+            false => Ok(()),
+        }
+    }
+
+    /// Wait for the simulated USB round trip.
+    async fn round_trip(&self) -> eyre::Result<()> {
+        tokio::time::sleep(self.latency()).await;
+        self.check_connected()
+    }
+
+    fn setting(&self, name: &str) -> String {
+        self.state.lock().settings[name].clone()
+    }
+
+    /// Decide whether the next capture fails, returning an error for failures that happen right away.
+    ///
+    /// Returns whether the capture's files should be corrupted otherwise.
+    fn inject_failure(&self) -> eyre::Result<bool> {
+        let mut state = self.state.lock();
+        let failures = &self.config.failures;
+        if failures.is_empty() || state.rng.uniform() >= self.config.failure_rate {
+            return Ok(false);
+        }
+        let failure = failures[state.rng.next_u64() as usize % failures.len()];
+        tracing::debug!(?failure, "Injecting simulated failure");
+        let err = match failure {
+            SimulatedFailure::Busy => DriverError::CameraBusy,
+            SimulatedFailure::Timeout => DriverError::Timeout,
+            SimulatedFailure::Corrupted => return Ok(true),
+            SimulatedFailure::Disconnect => {
+                state.disconnected = true;
+                return Err(disconnected_error());
+            }
+        };
+        Err(eyre::Report::new(err).wrap_err("Simulated failure"))
+    }
+
+    /// Noise-free signal in ADU for each pixel, before the colour filter.
+    fn render_sky(&self, exposure: Duration, gain: f64) -> Vec<f64> {
+        let (width, height) = (self.config.width as usize, self.config.height as usize);
+        let scale = exposure.as_secs_f64() * gain;
+        let mut signal = vec![self.config.sky_background * scale; width * height];
+        let radius = (4. * STAR_SIGMA).ceil() as isize;
+        for star in &self.stars {
+            let (cx, cy) = (star.x as isize, star.y as isize);
+            for y in (cy - radius).max(0)..(cy + radius + 1).min(height as isize) {
+                for x in (cx - radius).max(0)..(cx + radius + 1).min(width as isize) {
+                    let (dx, dy) = (x as f64 + 0.5 - star.x, y as f64 + 0.5 - star.y);
+                    let profile = (-(dx * dx + dy * dy) / (2. * STAR_SIGMA * STAR_SIGMA)).exp();
+                    signal[y as usize * width + x as usize] += star.flux * scale * profile;
+                }
+            }
+        }
+        signal
+    }
+
+    /// Add shot and read noise to a signal in ADU and offset it by the black level.
+    fn expose_pixel(&self, rng: &mut Rng, signal: f64, gain: f64) -> f64 {
+        let shot_noise = (signal * gain).sqrt() * rng.normal();
+        let read_noise = self.config.read_noise * gain * rng.normal();
+        f64::from(BLACK_LEVEL) + signal + shot_noise + read_noise
+    }
+
+    fn encode_dng(&self, rng: &mut Rng, sky: &[f64], gain: f64, exposure: Duration) -> Bytes {
+        let (width, height) = (self.config.width as usize, self.config.height as usize);
+        let cfa = rawler::CFA::new(&self.config.bayer_pattern);
+        let data = sky
+            .iter()
+            .enumerate()
+            .map(|(i, &signal)| {
+                let color = cfa.color_at(i / width, i % width);
+                let value = self.expose_pixel(rng, signal * CHANNEL_RESPONSE[color], gain);
+                value.round().clamp(0., WHITE_LEVEL.into()) as u16
+            })
+            .collect();
+
+        let mut camera = rawler::decoders::Camera::new();
+        camera.make = "Alpaca DSLR".to_owned();
+        camera.model = self.config.model.clone();
+        camera.clean_make = camera.make.clone();
+        camera.clean_model = camera.model.clone();
+        camera.cfa = cfa;
+        camera.plane_color = rawler::cfa::PlaneColor::new("RGB");
+        camera.real_bps = 14;
+        let [r, g, b] = CHANNEL_RESPONSE.map(|response| (1. / response) as f32);
+        let raw = RawImage::new(
+            camera.clone(),
+            rawler::pixarray::PixU16::new_with(data, width, height),
+            1,
+            [r, g, b, f32::NAN],
+            RawPhotometricInterpretation::Cfa(CFAConfig::new_from_camera(&camera)),
+            Some(BlackLevel::new(&[u32::from(BLACK_LEVEL); 4], 2, 2, 1)),
+            Some(WhiteLevel::new(vec![WHITE_LEVEL.into()])),
+            false,
+        );
+
+        let mut dng = std::io::Cursor::new(Vec::new());
+        let result = (|| {
+            let mut writer = DngWriter::new(&mut dng, DNG_VERSION_V1_4)?;
+            writer.load_base_tags(&raw)?;
+            writer.exif_ifd_mut().add_tag(
+                rawler::tags::ExifTag::ExposureTime,
+                rawler::formats::tiff::Rational::new(exposure_micros(exposure), 1_000_000),
+            );
+            let mut subframe = writer.subframe(0);
+            subframe.raw_image(
+                &raw,
+                CropMode::None,
+                DngCompression::Uncompressed,
+                DngPhotometricConversion::Original,
+                1,
+            )?;
+            subframe.finalize()?;
+            writer.close()
+        })();
+        result.expect("writing DNG to memory can't fail");
+        dng.into_inner().into()
+    }
+
+    fn encode_jpeg(&self, rng: &mut Rng, sky: &[f64], gain: f64, exposure: Duration) -> Bytes {
+        let full_range = f64::from(WHITE_LEVEL - BLACK_LEVEL);
+        let pixels = sky
+            .iter()
+            .flat_map(|&signal| CHANNEL_RESPONSE.map(|response| (signal, response)))
+            .map(|(signal, response)| {
+                let value =
+                    self.expose_pixel(rng, signal * response, gain) - f64::from(BLACK_LEVEL);
+                // Undo the channel response like in-camera white balance, and apply a gamma curve.
+                let value = (value / response / full_range).clamp(0., 1.).sqrt();
+                (value * 255.).round() as u8
+            })
+            .collect::<Vec<_>>();
+
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 90)
+            .encode(
+                &pixels,
+                self.config.width,
+                self.config.height,
+                image::ColorType::Rgb8,
+            )
+            .expect("writing JPEG to memory can't fail");
+
+        // Record the exposure time in an EXIF segment right after the start-of-image marker.
+        let exposure_time = exif::Field {
+            tag: exif::Tag::ExposureTime,
+            ifd_num: exif::In::PRIMARY,
+            value: exif::Value::Rational(vec![exif::Rational {
+                num: exposure_micros(exposure),
+                denom: 1_000_000,
+            }]),
+        };
+        let mut writer = exif::experimental::Writer::new();
+        writer.push_field(&exposure_time);
+        let mut tiff = std::io::Cursor::new(Vec::new());
+        writer
+            .write(&mut tiff, false)
+            .expect("writing EXIF to memory can't fail");
+        let tiff = tiff.into_inner();
+        let mut app1 = vec![0xff, 0xe1];
+        app1.extend(((2 + 6 + tiff.len()) as u16).to_be_bytes());
+        app1.extend(b"Exif\0\0");
+        app1.extend(tiff);
+        jpeg.splice(2..2, app1);
+        jpeg.into()
+    }
+
+    /// Render a frame in the current image format and store it on the card.
+    ///
+    /// Returns the new files, RAW first.
+    fn write_capture(&self, exposure: Duration, corrupted: bool) -> Vec<CameraFile> {
+        let image_format = self.setting("imageformat");
+        let gain = self.setting("iso").parse::<f64>().unwrap_or(100.) / 100.;
+        let (mut rng, number) = {
+            let mut state = self.state.lock();
+            let number = state.next_file_number;
+            state.next_file_number = number % 9999 + 1;
+            (Rng(state.rng.next_u64()), number)
+        };
+
+        let sky = self.render_sky(exposure, gain);
+        let mut files = Vec::new();
+        if image_format.contains("RAW") {
+            files.push((
+                format!("IMG_{number:04}.DNG"),
+                self.encode_dng(&mut rng, &sky, gain, exposure),
+            ));
+        }
+        if image_format.contains("JPEG") {
+            files.push((
+                format!("IMG_{number:04}.JPG"),
+                self.encode_jpeg(&mut rng, &sky, gain, exposure),
+            ));
+        }
+
+        let mut state = self.state.lock();
+        files
+            .into_iter()
+            .map(|(name, data)| {
+                state.files.insert(name.clone(), (data, corrupted));
+                CameraFile {
+                    folder: FOLDER.to_owned(),
+                    name,
+                }
+            })
+            .collect()
+    }
+
+    fn toggle_bulb(&self, on: bool) -> eyre::Result<()> {
+        self.check_connected()?;
+        if on {
+            let shutter_speed = self.setting("shutterspeed");
+            if shutter_speed != "bulb" {
+                return Err(eyre::Report::new(DriverError::CameraFailure)
+                    .wrap_err(format!("Shutter speed is {shutter_speed}, not bulb")));
+            }
+            let corrupted = self.inject_failure()?;
+            self.state.lock().bulb_opened = Some((Instant::now(), corrupted));
+        } else {
+            let opened = self.state.lock().bulb_opened.take();
+            if let Some((opened, corrupted)) = opened {
+                let files = self.write_capture(opened.elapsed(), corrupted);
+                // Like real cameras, report all files of a bulb capture as events.
+                self.state.lock().events.extend(files);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn exposure_micros(exposure: Duration) -> u32 {
+    exposure.as_micros().try_into().unwrap_or(u32::MAX)
+}
+
+#[async_trait]
+impl CameraBackend for SimulatedCamera {
+    async fn radio_config(&self, name: &str) -> eyre::Result<RadioConfig> {
+        self.round_trip().await?;
+        let choices = radio_choices(name)
+            .ok_or_else(|| eyre::eyre!("Simulated camera has no config widget {name}"))?;
+        Ok(RadioConfig {
+            choices: choices.iter().map(|&choice| choice.to_owned()).collect(),
+            current: self.setting(name),
+        })
+    }
+
+    async fn set_radio_config(&self, name: &str, value: &str) -> eyre::Result<()> {
+        self.round_trip().await?;
+        let choices = radio_choices(name)
+            .ok_or_else(|| eyre::eyre!("Simulated camera has no config widget {name}"))?;
+        eyre::ensure!(
+            choices.contains(&value),
+            "Invalid choice {value:?} for {name}"
+        );
+        self.state
+            .lock()
+            .settings
+            .insert(name.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    async fn serial_number(&self) -> Option<String> {
+        self.round_trip().await.ok()?;
+        Some(format!("SIM{:04}", self.index))
+    }
+
+    fn supports_bulb(&self) -> bool {
+        true
+    }
+
+    async fn set_bulb(&self, on: bool) -> eyre::Result<()> {
+        self.round_trip().await?;
+        // Releasing renders the frame, which takes a while on large sensors.
+        tokio::task::block_in_place(|| self.toggle_bulb(on))
+    }
+
+    fn set_bulb_blocking(&self, on: bool) -> eyre::Result<()> {
+        std::thread::sleep(self.latency());
+        self.toggle_bulb(on)
+    }
+
+    fn has_temperature(&self) -> bool {
+        true
+    }
+
+    async fn temperature(&self) -> eyre::Result<f64> {
+        self.round_trip().await?;
+        Ok(20. + 0.1 * self.state.lock().rng.normal())
+    }
+
+    async fn capture(&self) -> eyre::Result<CameraFile> {
+        self.round_trip().await?;
+        let shutter_speed = self.setting("shutterspeed");
+        let exposure = parse_speed(&shutter_speed).ok_or_else(|| {
+            eyre::Report::new(DriverError::CameraFailure)
+                .wrap_err(format!("Can't capture at shutter speed {shutter_speed}"))
+        })?;
+        let corrupted = self.inject_failure()?;
+        tokio::time::sleep(exposure).await;
+        let mut files = tokio::task::block_in_place(|| self.write_capture(exposure, corrupted));
+        let first = files.remove(0);
+        // Other files of the capture are only reported as events.
+        self.state.lock().events.extend(files);
+        Ok(first)
+    }
+
+    async fn wait_event(&self, timeout: Duration) -> eyre::Result<CameraEvent> {
+        self.round_trip().await?;
+        let event = self.state.lock().events.pop_front();
+        Ok(match event {
+            Some(file) => CameraEvent::NewFile(file),
+            None => {
+                tokio::time::sleep(timeout).await;
+                CameraEvent::Timeout
+            }
+        })
+    }
+
+    async fn download(&self, file: &CameraFile) -> eyre::Result<Bytes> {
+        self.round_trip().await?;
+        let (data, corrupted) = self
+            .state
+            .lock()
+            .files
+            .get(&file.name)
+            .cloned()
+            .ok_or_else(|| eyre::eyre!("No such file {} on the card", file.name))?;
+        if corrupted {
+            return Err(eyre::Report::new(DriverError::CorruptedData)
+                .wrap_err(format!("Simulated transfer error for {}", file.name)));
+        }
+        Ok(data)
+    }
+
+    async fn delete(&self, file: &CameraFile) -> eyre::Result<()> {
+        self.round_trip().await?;
+        self.state
+            .lock()
+            .files
+            .remove(&file.name)
+            .ok_or_else(|| eyre::eyre!("No such file {} on the card", file.name))?;
+        Ok(())
+    }
+}
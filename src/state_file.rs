@@ -0,0 +1,33 @@
+use eyre::Context;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+
+/// Load a TOML file from the state directory, or return the default value if it doesn't exist yet.
+pub(crate) fn load<T: DeserializeOwned + Default>(path: &Path) -> eyre::Result<T> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => toml::from_str(&contents)
+            .wrap_err_with(|| format!("Invalid state file {}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => {
+            Err(err).wrap_err_with(|| format!("Couldn't read state file {}", path.display()))
+        }
+    }
+}
+
+/// Save a value as a TOML file in the state directory, creating the directory if necessary.
+///
+/// The file is first written under a temporary name and then renamed, so that a crash
+/// in the middle doesn't leave a truncated file behind.
+pub(crate) fn save<T: Serialize>(path: &Path, value: &T) -> eyre::Result<()> {
+    (|| -> eyre::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, toml::to_string_pretty(value)?)?;
+        std::fs::rename(tmp_path, path)?;
+        Ok(())
+    })()
+    .wrap_err_with(|| format!("Couldn't write state file {}", path.display()))
+}
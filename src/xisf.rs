@@ -0,0 +1,233 @@
+use crate::fits::{format_date, ExposureMetadata};
+use ascom_alpaca::api::ImageArray;
+use flate2::write::ZlibEncoder;
+use std::fmt::Write as _;
+use std::io::Write as _;
+
+/// Attached data blocks are aligned to this many bytes.
+const BLOCK_ALIGNMENT: usize = 4096;
+const SIGNATURE: &[u8; 8] = b"XISF0100";
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Reorder bytes so that those at the same position within each item are grouped together,
+/// which makes 16-bit data compress much better.
+fn shuffle(data: &[u8], item_size: usize) -> Vec<u8> {
+    (0..item_size)
+        .flat_map(|offset| data.iter().skip(offset).step_by(item_size).copied())
+        .collect()
+}
+
+struct Properties(String);
+
+impl Properties {
+    fn string(&mut self, id: &str, value: &str) {
+        let _ = write!(
+            self.0,
+            r#"<Property id="{id}" type="String">{}</Property>"#,
+            escape(value)
+        );
+    }
+
+    fn value(&mut self, id: &str, kind: &str, value: impl std::fmt::Display) {
+        let _ = write!(
+            self.0,
+            r#"<Property id="{id}" type="{kind}" value="{value}"/>"#
+        );
+    }
+
+    fn fits_keyword(&mut self, name: &str, value: &str, comment: &str) {
+        let _ = write!(
+            self.0,
+            r#"<FITSKeyword name="{name}" value="{}" comment="{}"/>"#,
+            escape(value),
+            escape(comment)
+        );
+    }
+}
+
+/// Encode an image with its metadata as a monolithic XISF file, optionally zlib-compressed.
+pub(crate) fn encode(image: &ImageArray, metadata: &ExposureMetadata, compress: bool) -> Vec<u8> {
+    let (width, height, channels) = image.dim();
+    let frame = &metadata.frame;
+
+    // Planar little-endian samples, rows from the top.
+    let mut data = Vec::with_capacity(width * height * channels * 2);
+    for channel in 0..channels {
+        for y in 0..height {
+            for x in 0..width {
+                let value = image[[x, y, channel]].clamp(0, u16::MAX.into()) as u16;
+                data.extend(value.to_le_bytes());
+            }
+        }
+    }
+    let mut compression = String::new();
+    if compress {
+        let uncompressed_size = data.len();
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder
+            .write_all(&shuffle(&data, 2))
+            .expect("writing to memory can't fail");
+        data = encoder.finish().expect("writing to memory can't fail");
+        compression = format!(r#" compression="zlib+sh:{uncompressed_size}:2""#);
+    }
+
+    let mut properties = Properties(String::new());
+    properties.value(
+        "Observation:Time:Start",
+        "TimePoint",
+        format!("{}Z", format_date(frame.start)),
+    );
+    if let Some(target) = &frame.target {
+        properties.string("Observation:Object:Name", target);
+    }
+    properties.value("Instrument:ExposureTime", "Float32", frame.exposure);
+    if let Some(instrument) = &metadata.instrument {
+        properties.string("Instrument:Camera:Name", instrument);
+    }
+    if let Ok(iso) = frame.iso.parse::<i32>() {
+        properties.value("Instrument:Camera:ISOSpeed", "Int32", iso);
+    }
+    if let Some(electrons_per_adu) = metadata.electrons_per_adu {
+        properties.value("Instrument:Camera:Gain", "Float32", electrons_per_adu);
+    }
+    properties.value("Instrument:Camera:XBinning", "Int32", metadata.bin);
+    properties.value("Instrument:Camera:YBinning", "Int32", metadata.bin);
+    if let Some(temperature) = metadata.temperature {
+        properties.value("Instrument:Sensor:Temperature", "Float32", temperature);
+    }
+    let mut cfa = String::new();
+    if let Some((x, y)) = metadata.bayer_offset {
+        let pattern = rawler::CFA::new("RGGB").shift(x as usize, y as usize).name;
+        properties.string("PCL:CFASourcePattern", &pattern);
+        cfa = format!(r#"<ColorFilterArray pattern="{pattern}" width="2" height="2"/>"#);
+    }
+    // XISF has no standard properties for these, but PixInsight understands FITS keywords.
+    properties.fits_keyword(
+        "IMAGETYP",
+        &format!("'{}'", frame.image_type()),
+        "type of exposure",
+    );
+    properties.fits_keyword(
+        "XORGSUBF",
+        &metadata.origin.0.to_string(),
+        "subframe origin in X",
+    );
+    properties.fits_keyword(
+        "YORGSUBF",
+        &metadata.origin.1.to_string(),
+        "subframe origin in Y",
+    );
+
+    let color_space = if channels > 1 { "RGB" } else { "Gray" };
+    let build_header = |position: usize| {
+        format!(
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                r#"<xisf version="1.0" xmlns="http://www.pixinsight.com/xisf">"#,
+                r#"<Image geometry="{width}:{height}:{channels}" sampleFormat="UInt16" "#,
+                r#"colorSpace="{color_space}" pixelStorage="Planar" "#,
+                r#"location="attachment:{position}:{size}"{compression}>"#,
+                "{properties}{cfa}</Image>",
+                r#"<Metadata><Property id="XISF:CreatorApplication" type="String">{creator}</Property></Metadata>"#,
+                "</xisf>"
+            ),
+            width = width,
+            height = height,
+            channels = channels,
+            color_space = color_space,
+            position = position,
+            size = data.len(),
+            compression = compression,
+            properties = properties.0,
+            cfa = cfa,
+            creator = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")),
+        )
+    };
+
+    // The data position is written into the header itself, so find a stable one.
+    let mut position = 0;
+    let header = loop {
+        let header = build_header(position);
+        let aligned = (SIGNATURE.len() + 8 + header.len()).next_multiple_of(BLOCK_ALIGNMENT);
+        if aligned == position {
+            break header;
+        }
+        position = aligned;
+    };
+
+    let mut xisf = Vec::with_capacity(position + data.len());
+    xisf.extend(SIGNATURE);
+    xisf.extend((header.len() as u32).to_le_bytes());
+    // Reserved.
+    xisf.extend([0; 4]);
+    xisf.extend(header.bytes());
+    xisf.resize(position, 0);
+    xisf.extend(data);
+    xisf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::FrameInfo;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read as _;
+
+    #[test]
+    fn escapes_xml() {
+        assert_eq!(
+            escape(r#"<a href="x">&</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn encodes_bias_frame_layout() {
+        let image: ImageArray =
+            ndarray::Array3::from_shape_fn((3, 2, 1), |(x, y, _)| (x + 10 * y) as u16).into();
+        let metadata = ExposureMetadata {
+            frame: FrameInfo {
+                start: std::time::SystemTime::UNIX_EPOCH,
+                exposure: 0.,
+                iso: "800".to_owned(),
+                light: false,
+                bias: true,
+                target: None,
+            },
+            instrument: None,
+            electrons_per_adu: None,
+            bayer_offset: Some((1, 1)),
+            bin: 1,
+            origin: (0, 0),
+            temperature: None,
+        };
+
+        for compress in [false, true] {
+            let xisf = encode(&image, &metadata, compress);
+            assert_eq!(&xisf[..8], SIGNATURE);
+            let header_len = u32::from_le_bytes(xisf[8..12].try_into().unwrap()) as usize;
+            let header = std::str::from_utf8(&xisf[16..16 + header_len]).unwrap();
+            assert!(header.contains(r#"geometry="3:2:1""#), "{header}");
+            assert!(header.contains(r#"<ColorFilterArray pattern="BGGR""#));
+            assert!(header.contains(r#"name="IMAGETYP" value="'Bias Frame'""#));
+
+            // Rows go from the top, as little-endian 16-bit samples.
+            let expected = [0, 0, 1, 0, 2, 0, 10, 0, 11, 0, 12, 0];
+            let data = &xisf[BLOCK_ALIGNMENT..];
+            if compress {
+                let mut shuffled = Vec::new();
+                ZlibDecoder::new(data).read_to_end(&mut shuffled).unwrap();
+                assert_eq!(shuffled, shuffle(&expected, 2));
+            } else {
+                assert_eq!(data, expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,486 @@
+//! End-to-end tests driving the Alpaca HTTP API against simulated cameras.
+//!
+//! All tests share one in-process driver, since the discovery server always binds the
+//! same UDP port, and each test uses its own simulated camera so they can run in parallel.
+
+use alpaca_dslr::{Config, Driver};
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+const CAMERAS: usize = 9;
+const WIDTH: u64 = 64;
+const HEIGHT: u64 = 48;
+
+const INVALID_VALUE: i64 = 0x401;
+const VALUE_NOT_SET: i64 = 0x402;
+const NOT_CONNECTED: i64 = 0x407;
+const INVALID_OPERATION: i64 = 0x40B;
+
+const IDLE: i64 = 0;
+const EXPOSING: i64 = 2;
+const ERROR: i64 = 5;
+
+/// Long enough to be taken in bulb mode and to stop or abort it halfway.
+const BULB_DURATION: &str = "20";
+/// Snaps to the 1/125 shutter speed of the simulator.
+const SHORT_DURATION: &str = "0.01";
+
+/// Start the driver on a background runtime once and return its address.
+fn server_addr() -> SocketAddr {
+    static ADDR: OnceLock<SocketAddr> = OnceLock::new();
+
+    *ADDR.get_or_init(|| {
+        let state_dir =
+            std::env::temp_dir().join(format!("alpaca-dslr-test-{}", std::process::id()));
+        std::fs::create_dir_all(&state_dir).unwrap();
+
+        let config = Config::parse(&format!(
+            r#"
+            state_dir = {state_dir:?}
+
+            [server]
+            listen_addr = "127.0.0.1"
+            port = 0
+
+            [cameras]
+            include = ["Simulated"]
+            spare_slots = 0
+            rescan_interval = 0
+
+            [simulator]
+            cameras = {CAMERAS}
+            width = {WIDTH}
+            height = {HEIGHT}
+            stars = 5
+            latency = 0.05
+            seed = 1
+            "#
+        ))
+        .unwrap();
+
+        let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            // The driver blocks in place for CPU-heavy work, which needs a multi-threaded runtime.
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+
+            runtime
+                .block_on(async {
+                    let driver = Driver::new(config).await?;
+                    addr_tx.send(driver.listen_addr()).unwrap();
+                    driver.run(std::future::pending()).await
+                })
+                .unwrap();
+        });
+
+        addr_rx
+            .recv_timeout(Duration::from_secs(30))
+            .expect("driver didn't start")
+    })
+}
+
+/// Error returned by an Alpaca method.
+#[derive(Debug)]
+struct AlpacaError {
+    number: i64,
+    message: String,
+}
+
+/// Minimal Alpaca client for one camera device.
+struct Camera {
+    device: usize,
+}
+
+impl Camera {
+    fn new(device: usize) -> Self {
+        Self { device }
+    }
+
+    fn connected(device: usize) -> Self {
+        let camera = Self::new(device);
+        camera.put("connected", &[("Connected", "true")]);
+        camera
+    }
+
+    /// Send a plain HTTP/1.0 request, so that the response is neither chunked nor kept alive.
+    fn try_request(
+        &self,
+        method: &str,
+        action: &str,
+        params: &[(&str, &str)],
+    ) -> Result<Value, AlpacaError> {
+        // None of the values we send need URL encoding.
+        let query = [("ClientID", "1"), ("ClientTransactionID", "1")]
+            .iter()
+            .chain(params)
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("&");
+        let path = format!("/api/v1/camera/{}/{action}", self.device);
+
+        let request = match method {
+            "GET" => format!("GET {path}?{query} HTTP/1.0\r\n\r\n"),
+            _ => format!(
+                "{method} {path} HTTP/1.0\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{query}",
+                query.len()
+            ),
+        };
+
+        let mut stream = TcpStream::connect(server_addr()).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .unwrap_or_else(|| panic!("malformed response to {method} {action}: {response:?}"));
+        assert!(
+            head.starts_with("HTTP/1.0 200") || head.starts_with("HTTP/1.1 200"),
+            "{method} {action} failed: {response}"
+        );
+
+        let mut body: Value = serde_json::from_str(body).unwrap();
+        match body["ErrorNumber"].as_i64().unwrap_or(0) {
+            0 => Ok(body["Value"].take()),
+            number => Err(AlpacaError {
+                number,
+                message: body["ErrorMessage"].as_str().unwrap_or_default().to_owned(),
+            }),
+        }
+    }
+
+    fn try_get(&self, action: &str) -> Result<Value, AlpacaError> {
+        self.try_request("GET", action, &[])
+    }
+
+    fn try_put(&self, action: &str, params: &[(&str, &str)]) -> Result<Value, AlpacaError> {
+        self.try_request("PUT", action, params)
+    }
+
+    fn get(&self, action: &str) -> Value {
+        self.try_get(action)
+            .unwrap_or_else(|err| panic!("GET {action} failed: {err:?}"))
+    }
+
+    fn put(&self, action: &str, params: &[(&str, &str)]) {
+        if let Err(err) = self.try_put(action, params) {
+            panic!("PUT {action} {params:?} failed: {err:?}");
+        }
+    }
+
+    fn get_i64(&self, action: &str) -> i64 {
+        self.get(action).as_i64().unwrap()
+    }
+
+    fn state(&self) -> i64 {
+        self.get_i64("camerastate")
+    }
+
+    fn start_exposure(&self, duration: &str) -> Result<Value, AlpacaError> {
+        self.try_put(
+            "startexposure",
+            &[("Duration", duration), ("Light", "true")],
+        )
+    }
+
+    /// Poll until `done` accepts the camera state, returning the distinct states seen on the way.
+    fn wait_until(&self, done: impl Fn(i64) -> bool) -> Vec<i64> {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let mut seen = Vec::new();
+        loop {
+            let current = self.state();
+            if seen.last() != Some(&current) {
+                seen.push(current);
+            }
+            if done(current) {
+                return seen;
+            }
+            assert!(Instant::now() < deadline, "camera got stuck, saw {seen:?}");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Poll until the exposure finished, successfully or not.
+    fn wait_for_exposure(&self) -> Vec<i64> {
+        self.wait_until(|state| state == IDLE || state == ERROR)
+    }
+
+    /// Take a short exposure and return the image.
+    fn expose(&self) -> Value {
+        self.start_exposure(SHORT_DURATION).unwrap();
+        let states = self.wait_for_exposure();
+        assert_eq!(states.last(), Some(&IDLE), "exposure failed: {states:?}");
+        assert_eq!(self.get("imageready"), true);
+        self.get("imagearray")
+    }
+}
+
+#[track_caller]
+fn assert_error(result: Result<Value, AlpacaError>, number: i64) {
+    match result {
+        Ok(value) => panic!("expected error {number:#X}, got {value}"),
+        Err(err) => assert_eq!(err.number, number, "unexpected error: {}", err.message),
+    }
+}
+
+/// Rank and dimensions of an image array, with `Value` indexed as [x][y] or [x][y][channel].
+fn image_shape(image: &Value) -> (i64, Vec<usize>) {
+    let rank = image["Rank"].as_i64().unwrap();
+    let mut shape = Vec::new();
+    let mut value = &image["Value"];
+    while let Some(values) = value.as_array() {
+        shape.push(values.len());
+        value = &values[0];
+    }
+    assert_eq!(shape.len() as i64, rank, "rank doesn't match the array");
+    (rank, shape)
+}
+
+#[test]
+fn connect_and_disconnect() {
+    let camera = Camera::new(0);
+
+    assert_eq!(camera.get("connected"), false);
+    assert_error(camera.try_get("camerastate"), NOT_CONNECTED);
+    assert_error(camera.start_exposure(SHORT_DURATION), NOT_CONNECTED);
+
+    camera.put("connected", &[("Connected", "true")]);
+    assert_eq!(camera.get("connected"), true);
+    assert_eq!(camera.state(), IDLE);
+    assert_eq!(camera.get_i64("cameraxsize"), WIDTH as i64);
+    assert_eq!(camera.get_i64("cameraysize"), HEIGHT as i64);
+    assert_eq!(camera.get("imageready"), false);
+
+    camera.put("connected", &[("Connected", "false")]);
+    assert_eq!(camera.get("connected"), false);
+    assert_error(camera.try_get("camerastate"), NOT_CONNECTED);
+}
+
+#[test]
+fn timed_exposure() {
+    let camera = Camera::connected(1);
+
+    assert_error(camera.try_get("imagearray"), INVALID_OPERATION);
+    assert_error(camera.try_get("lastexposureduration"), INVALID_OPERATION);
+
+    camera.start_exposure(SHORT_DURATION).unwrap();
+    let states = camera.wait_for_exposure();
+    assert!(
+        states.len() >= 2
+            && states[..states.len() - 1]
+                .iter()
+                .all(|s| (1..=4).contains(s)),
+        "expected the exposure to progress through intermediate states, saw {states:?}"
+    );
+    assert_eq!(states.last(), Some(&IDLE));
+
+    assert_eq!(camera.get("imageready"), true);
+    assert_eq!(camera.get_i64("percentcompleted"), 100);
+    let duration = camera.get("lastexposureduration").as_f64().unwrap();
+    assert!(
+        (duration - 1. / 125.).abs() < 1e-4,
+        "expected the 1/125 shutter speed, got {duration}"
+    );
+
+    let image = camera.get("imagearray");
+    assert_eq!(
+        image_shape(&image),
+        (2, vec![WIDTH as usize, HEIGHT as usize])
+    );
+
+    // The image stays available until the next exposure.
+    assert_eq!(camera.get("imagearray"), image);
+}
+
+#[test]
+fn stop_bulb_exposure() {
+    let camera = Camera::connected(2);
+
+    camera.start_exposure(BULB_DURATION).unwrap();
+    let states = camera.wait_until(|state| state == EXPOSING);
+    assert!(
+        !states.contains(&IDLE),
+        "camera went idle before exposing: {states:?}"
+    );
+    assert_eq!(camera.get("imageready"), false);
+
+    // Stopping returns once the exposure is done, with the image read out.
+    camera.put("stopexposure", &[]);
+    assert_eq!(camera.state(), IDLE);
+    assert_eq!(camera.get("imageready"), true);
+
+    let duration = camera.get("lastexposureduration").as_f64().unwrap();
+    assert!(
+        duration > 0. && duration < 20.,
+        "expected the exposure to be cut short, got {duration}"
+    );
+    assert_eq!(
+        image_shape(&camera.get("imagearray")),
+        (2, vec![WIDTH as usize, HEIGHT as usize])
+    );
+}
+
+#[test]
+fn abort_bulb_exposure() {
+    let camera = Camera::connected(3);
+
+    camera.start_exposure(BULB_DURATION).unwrap();
+    camera.wait_until(|state| state == EXPOSING);
+
+    camera.put("abortexposure", &[]);
+    assert_eq!(camera.state(), ERROR);
+    assert_eq!(camera.get("imageready"), false);
+    assert_error(camera.try_get("imagearray"), INVALID_OPERATION);
+
+    // Stopping or aborting when there's no exposure in progress is a no-op.
+    camera.put("abortexposure", &[]);
+    camera.put("stopexposure", &[]);
+    assert_eq!(camera.state(), ERROR);
+
+    // The next exposure recovers from the error.
+    let image = camera.expose();
+    assert_eq!(camera.state(), IDLE);
+    assert_eq!(
+        image_shape(&image),
+        (2, vec![WIDTH as usize, HEIGHT as usize])
+    );
+}
+
+#[test]
+fn exposure_errors() {
+    let camera = Camera::connected(4);
+
+    assert_error(camera.start_exposure("-1"), INVALID_VALUE);
+    assert_eq!(camera.state(), IDLE);
+
+    camera.start_exposure(BULB_DURATION).unwrap();
+    assert_error(camera.start_exposure(SHORT_DURATION), INVALID_OPERATION);
+    assert_error(camera.try_get("imagearray"), INVALID_OPERATION);
+
+    camera.put("abortexposure", &[]);
+    assert_eq!(camera.state(), ERROR);
+}
+
+#[test]
+fn subframe() {
+    let camera = Camera::connected(5);
+
+    camera.put("binx", &[("BinX", "2")]);
+    assert_eq!(camera.get_i64("biny"), 2);
+    assert_eq!(camera.get_i64("numx"), WIDTH as i64 / 2);
+    assert_eq!(camera.get_i64("numy"), HEIGHT as i64 / 2);
+
+    for (action, name, value) in [
+        ("startx", "StartX", "4"),
+        ("starty", "StartY", "2"),
+        ("numx", "NumX", "16"),
+        ("numy", "NumY", "12"),
+    ] {
+        camera.put(action, &[(name, value)]);
+    }
+
+    let (_, shape) = image_shape(&camera.expose());
+    assert_eq!(shape[..2], [16, 12]);
+
+    // Out of bounds subframes are accepted on set, but rejected when starting the exposure.
+    camera.put("startx", &[("StartX", "20")]);
+    assert_error(camera.start_exposure(SHORT_DURATION), INVALID_VALUE);
+    assert_eq!(camera.state(), IDLE);
+    // The previous image is still there.
+    assert_eq!(camera.get("imageready"), true);
+
+    assert_error(camera.try_put("numx", &[("NumX", "-1")]), INVALID_VALUE);
+    assert_error(camera.try_put("binx", &[("BinX", "0")]), INVALID_VALUE);
+
+    // Unbinned, the same subframe fits again.
+    camera.put("binx", &[("BinX", "1")]);
+    let (_, shape) = image_shape(&camera.expose());
+    assert_eq!(shape[..2], [16, 12]);
+}
+
+#[test]
+fn gain() {
+    let camera = Camera::connected(6);
+
+    let gains = camera.get("gains");
+    assert_eq!(
+        gains,
+        serde_json::json!(["100", "200", "400", "800", "1600", "3200", "6400"])
+    );
+    // The simulator starts at ISO 800.
+    assert_eq!(camera.get_i64("gain"), 3);
+
+    camera.put("gain", &[("Gain", "0")]);
+    assert_eq!(camera.get_i64("gain"), 0);
+
+    assert_error(camera.try_put("gain", &[("Gain", "7")]), INVALID_VALUE);
+    assert_error(camera.try_put("gain", &[("Gain", "-1")]), INVALID_VALUE);
+    assert_eq!(camera.get_i64("gain"), 0);
+
+    // The selected ISO is applied to the camera when exposing.
+    camera.expose();
+    assert_eq!(camera.get_i64("gain"), 0);
+}
+
+#[test]
+fn readout_mode() {
+    let camera = Camera::connected(7);
+
+    assert_eq!(
+        camera.get("readoutmodes"),
+        serde_json::json!([
+            "RAW",
+            "RAW (debayered)",
+            "RAW + Large Fine JPEG",
+            "RAW + Large Fine JPEG (debayered)",
+            "Large Fine JPEG",
+        ])
+    );
+    assert_eq!(camera.get_i64("readoutmode"), 0);
+
+    camera.put("readoutmode", &[("ReadoutMode", "1")]);
+    assert_eq!(camera.get_i64("readoutmode"), 1);
+    assert_eq!(
+        image_shape(&camera.expose()),
+        (3, vec![WIDTH as usize, HEIGHT as usize, 3])
+    );
+
+    camera.put("readoutmode", &[("ReadoutMode", "4")]);
+    assert_eq!(
+        image_shape(&camera.expose()),
+        (3, vec![WIDTH as usize, HEIGHT as usize, 3])
+    );
+
+    assert_error(
+        camera.try_put("readoutmode", &[("ReadoutMode", "5")]),
+        INVALID_VALUE,
+    );
+    assert_eq!(camera.get_i64("readoutmode"), 4);
+}
+
+#[test]
+fn temperature_from_widget() {
+    let camera = Camera::connected(8);
+
+    // Temperature isn't read from the camera in the middle of an exposure.
+    camera.start_exposure(BULB_DURATION).unwrap();
+    assert_error(camera.try_get("ccdtemperature"), VALUE_NOT_SET);
+    camera.put("abortexposure", &[]);
+
+    // Simulated frames don't record temperature, like Nikon ones, so it's read from the
+    // config widget after download and stays known during the next exposure.
+    camera.expose();
+    camera.start_exposure(BULB_DURATION).unwrap();
+    let temperature = camera.get("ccdtemperature").as_f64().unwrap();
+    assert!(
+        (temperature - 20.).abs() < 1.,
+        "unexpected temperature {temperature}"
+    );
+    camera.put("abortexposure", &[]);
+}